@@ -0,0 +1,302 @@
+use log::debug;
+use regex::bytes::Regex;
+use std::*;
+
+use crate::ripgrep_json::{Data, Match, SubMatch};
+
+/// Format of the lines read by cg.
+#[derive(Clone, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum InputFormat {
+    /// Guess the format from the first line: rg JSON if it starts with `{`, grep otherwise
+    #[default]
+    Auto,
+    /// Records of `rg --json`
+    RgJson,
+    /// `path:line:text`, as printed by `grep -rn` or `git grep -n`
+    Grep,
+    /// `path:line:column:text`, as printed by `rg --vimgrep`, `ag --vimgrep` or compilers
+    Vimgrep,
+}
+
+/// Parser turning the lines read by cg into rg records.
+pub trait InputParser {
+    /// Parse one line, without its line break, return the records it produces. The line might
+    /// not be valid UTF-8.
+    fn parse_line(&mut self, line: &[u8]) -> Result<Vec<Match>, String>;
+    /// Return the records to add at the end of the input.
+    fn finish(&mut self) -> Vec<Match>;
+}
+
+pub fn make_parser(format: &InputFormat) -> Box<dyn InputParser> {
+    match format {
+        InputFormat::Auto => Box::new(AutoParser { parser: None }),
+        InputFormat::RgJson => Box::new(RgJsonParser {}),
+        InputFormat::Grep => Box::new(GrepParser::new(false)),
+        InputFormat::Vimgrep => Box::new(GrepParser::new(true)),
+    }
+}
+
+/// Parser of the records of `rg --json`, one record per line.
+pub struct RgJsonParser {}
+
+impl InputParser for RgJsonParser {
+    fn parse_line(&mut self, line: &[u8]) -> Result<Vec<Match>, String> {
+        serde_json::from_slice::<Match>(line)
+            .map(|record| vec![record])
+            .map_err(|e| {
+                debug!("{e:?}");
+                format!(
+                    "Received record from rg with unsopported format: {}",
+                    String::from_utf8_lossy(line)
+                )
+            })
+    }
+
+    fn finish(&mut self) -> Vec<Match> {
+        vec![]
+    }
+}
+
+/// Parser of the grep-like formats `path:line:text` and, with a column, `path:line:column:text`.
+///
+/// Begin and end records are generated when the path changes. The column is a 1-based byte
+/// offset that becomes an empty submatch. The locations printed by rustc below its messages,
+/// `--> path:line:column`, are read in both formats, without their column since they have no
+/// text. Lines that do not follow the format, like the `--` separators of `grep -C`, are ignored.
+/// Paths and lines that are not valid UTF-8 are kept as bytes.
+pub struct GrepParser {
+    re: Regex,
+    location_re: Regex,
+    path: Option<Vec<u8>>,
+}
+
+impl GrepParser {
+    pub fn new(column: bool) -> Self {
+        let re = match column {
+            true => r"(?s-u)^(?P<path>.+?):(?P<line>\d+):(?P<column>\d+):(?P<text>.*)$",
+            false => r"(?s-u)^(?P<path>.+?):(?P<line>\d+):(?P<text>.*)$",
+        };
+        GrepParser {
+            re: Regex::new(re).unwrap(),
+            location_re: Regex::new(r"(?s-u)^\s*--> (?P<path>.+?):(?P<line>\d+):\d+$").unwrap(),
+            path: None,
+        }
+    }
+
+    fn end(&mut self) -> Option<Match> {
+        self.path.take().map(|path| Match::End {
+            path: Data::from_bytes(path),
+            binary_offset: None,
+            stats: None,
+        })
+    }
+}
+
+impl InputParser for GrepParser {
+    fn parse_line(&mut self, line: &[u8]) -> Result<Vec<Match>, String> {
+        let Some(captures) = self
+            .location_re
+            .captures(line)
+            .or_else(|| self.re.captures(line))
+        else {
+            debug!("ignoring line: {:?}", String::from_utf8_lossy(line));
+            return Ok(vec![]);
+        };
+
+        // The digits of the line number and of the column are ASCII
+        let number = |digits: &[u8]| String::from_utf8_lossy(digits).parse::<u32>();
+        let path = captures["path"].to_vec();
+        let line_number = number(&captures["line"]).map_err(|e| {
+            format!(
+                "invalid line number in {:?}: {e}",
+                String::from_utf8_lossy(line)
+            )
+        })?;
+        let text = captures
+            .name("text")
+            .map(|text| text.as_bytes().to_vec())
+            .unwrap_or_default();
+
+        let submatches = match captures.name("column") {
+            Some(column) => {
+                let column = number(column.as_bytes()).map_err(|e| {
+                    format!("invalid column in {:?}: {e}", String::from_utf8_lossy(line))
+                })?;
+                // Keep the offset inside the line
+                let start = cmp::min(column.saturating_sub(1), text.len() as u32);
+                vec![SubMatch {
+                    submatch: Data::Text {
+                        text: String::new(),
+                    },
+                    start,
+                    end: start,
+                }]
+            }
+            None => vec![],
+        };
+
+        let mut records = vec![];
+        if self.path.as_ref() != Some(&path) {
+            records.extend(self.end());
+            records.push(Match::Begin {
+                path: Data::from_bytes(path.clone()),
+            });
+            self.path = Some(path.clone());
+        }
+
+        records.push(Match::Match {
+            path: Data::from_bytes(path),
+            lines: Data::from_bytes(text),
+            line_number,
+            absolute_offset: 0,
+            submatches,
+        });
+
+        Ok(records)
+    }
+
+    fn finish(&mut self) -> Vec<Match> {
+        self.end().into_iter().collect()
+    }
+}
+
+/// Parser choosing the format from the first line.
+pub struct AutoParser {
+    parser: Option<Box<dyn InputParser>>,
+}
+
+impl InputParser for AutoParser {
+    fn parse_line(&mut self, line: &[u8]) -> Result<Vec<Match>, String> {
+        let parser = self.parser.get_or_insert_with(|| {
+            let format = if line.iter().find(|c| !c.is_ascii_whitespace()) == Some(&b'{') {
+                InputFormat::RgJson
+            } else {
+                InputFormat::Grep
+            };
+            debug!("input format: {format:?}");
+            make_parser(&format)
+        });
+
+        parser.parse_line(line)
+    }
+
+    fn finish(&mut self) -> Vec<Match> {
+        self.parser
+            .as_mut()
+            .map(|parser| parser.finish())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grep_parser() {
+        let mut parser = GrepParser::new(false);
+
+        let records = parser.parse_line(b"src/main.rs:12:fn main() {").unwrap();
+        assert_eq!(2, records.len());
+        assert!(matches!(&records[0], Match::Begin { path } if path.as_bytes() == b"src/main.rs"));
+        match &records[1] {
+            Match::Match {
+                line_number,
+                lines,
+                submatches,
+                ..
+            } => {
+                assert_eq!(12, *line_number);
+                assert_eq!(b"fn main() {", lines.as_bytes());
+                assert!(submatches.is_empty());
+            }
+            m => panic!("expected a match record, got {m:?}"),
+        }
+
+        // Same file, the digits after the line number are text
+        let records = parser
+            .parse_line(b"src/main.rs:14:12:30:01 started")
+            .unwrap();
+        assert_eq!(1, records.len());
+        match &records[0] {
+            Match::Match {
+                lines, submatches, ..
+            } => {
+                assert_eq!(b"12:30:01 started", lines.as_bytes());
+                assert!(submatches.is_empty());
+            }
+            m => panic!("expected a match record, got {m:?}"),
+        }
+
+        assert!(parser.parse_line(b"--").unwrap().is_empty());
+
+        let records = parser.parse_line(b"README.md:1:# cgvg").unwrap();
+        assert!(
+            matches!(&records[0], Match::End { path, .. } if path.as_bytes() == b"src/main.rs")
+        );
+        assert!(matches!(&records[1], Match::Begin { path } if path.as_bytes() == b"README.md"));
+
+        // `grep -rn` in a Latin-1 tree
+        let records = parser.parse_line(b"caf\xe9.txt:1:caf\xe9").unwrap();
+        match &records[2] {
+            Match::Match { path, lines, .. } => {
+                assert!(matches!(path, Data::Bytes { bytes } if bytes == b"caf\xe9.txt"));
+                assert!(matches!(lines, Data::Bytes { bytes } if bytes == b"caf\xe9"));
+            }
+            m => panic!("expected a match record, got {m:?}"),
+        }
+
+        let records = parser.finish();
+        assert!(
+            matches!(&records[0], Match::End { path, .. } if path.as_bytes() == b"caf\xe9.txt")
+        );
+    }
+
+    #[test]
+    fn test_vimgrep_parser() {
+        let mut parser = GrepParser::new(true);
+
+        // As printed by `ag --vimgrep`
+        let records = parser.parse_line(b"src/main.rs:14:5:    foo: bar").unwrap();
+        match &records[1] {
+            Match::Match {
+                lines, submatches, ..
+            } => {
+                assert_eq!(b"    foo: bar", lines.as_bytes());
+                assert_eq!((4, 4), (submatches[0].start, submatches[0].end));
+            }
+            m => panic!("expected a match record, got {m:?}"),
+        }
+
+        // Location of rustc, in both formats
+        for column in [true, false] {
+            let mut parser = GrepParser::new(column);
+            let records = parser.parse_line(b"  --> src/main.rs:12:5").unwrap();
+            assert!(
+                matches!(&records[0], Match::Begin { path } if path.as_bytes() == b"src/main.rs")
+            );
+            match &records[1] {
+                Match::Match {
+                    line_number, lines, ..
+                } => assert_eq!((12, b"".as_slice()), (*line_number, lines.as_bytes())),
+                m => panic!("expected a match record, got {m:?}"),
+            }
+        }
+
+        // A line without column is not read as `path:line:text`
+        assert!(parser.parse_line(b"README.md:1:# cgvg").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_auto_parser() {
+        let mut parser = make_parser(&InputFormat::Auto);
+        let records = parser
+            .parse_line(br#"{"type":"begin","data":{"path":{"text":"src/a.rs"}}}"#)
+            .unwrap();
+        assert!(matches!(&records[0], Match::Begin { .. }));
+        assert!(parser.parse_line(b"src/a.rs:1:foo").is_err());
+
+        let mut parser = make_parser(&InputFormat::Auto);
+        assert_eq!(2, parser.parse_line(b"src/a.rs:1:foo").unwrap().len());
+    }
+}
@@ -1,52 +1,270 @@
 use clap::{error::ErrorKind, Parser};
 use log::{debug, info, warn};
 use regex::Regex;
-use rgvg::common::{expand_path, save_text, Index};
-use std::env;
+use rgvg::common::{
+    archive_search, find_executable, previous_searches, CgVgError, Index, SearchInfo, Store,
+    StoreWriter,
+};
+use rgvg::config::{ColorMode, Config, StoreScope, ViewKind};
 use std::process::ExitCode;
+use std::{env, fs, mem, path, time};
 use terminal_size::terminal_size;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
 
 mod views;
-use views::match_view;
+use views::{history_view, make_view, stats_view};
+
+mod theme;
+use theme::{use_colors, Theme, COLORS_ENV};
 
 mod ripgrep_json;
 use ripgrep_json::Match;
 
-mod print_terminal;
-use print_terminal::{number_of_digits, pad_number, wrap_text};
+mod input;
+use input::{make_parser, InputFormat};
 
-static DEFAULT_MATCH_FILE: &'static str = "~/.cgvg.match";
-static DEFAULT_RG: &'static str = "rg";
-static DEFAULT_RG_ARGS: [&str; 3] = ["--json", "--sort", "path"];
+mod replay;
+use replay::{glob_regex, parse_range, replay_records, ReplayFilter};
+
+mod print_terminal;
+use print_terminal::{
+    human_bytes, local_date, number_of_digits, pad_number, truncate_middle, wrap_text,
+};
 
 /// cg find code using ripgrep
+///
+/// Defaults of the options can be set in the configuration file `$XDG_CONFIG_HOME/cgvg/config.toml`.
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Args {
-    /// Place match file of rgvg
-    #[arg(short, long, default_value = DEFAULT_MATCH_FILE)]
-    match_file: String,
-    /// Binary name of rg, or path
-    #[arg(short, long, default_value = DEFAULT_RG)]
-    rg_bin_path: String,
+    /// Place match file of rgvg [default: ~/.cgvg.match]
+    #[arg(short, long)]
+    match_file: Option<String>,
+    /// path to index state file of rgvg [default: ~/.cgvg.idx]
+    // No short option, `-i` is used by rg for --ignore-case
+    #[arg(long)]
+    index_file: Option<String>,
+    /// Which searches share the match file and the history: global, or one per project,
+    /// directory or terminal [default: global]
+    #[arg(long, value_enum)]
+    scope: Option<StoreScope>,
+    /// Binary name of rg, or path [default: rg]
+    #[arg(short, long)]
+    rg_bin_path: Option<String>,
+    /// Matched lines longer than this are not printed, 0 prints all the lines [default: 500]
+    #[arg(long)]
+    max_text_size: Option<u32>,
+    /// Format of the lines read from stdin with `cg -` [default: auto]
+    #[arg(long, value_enum)]
+    input: Option<InputFormat>,
+    /// How to print the results [default: grouped]
+    #[arg(long, value_enum)]
+    view: Option<ViewKind>,
+    /// Wait for the end of the search to print the results, so the columns of all the files are
+    /// aligned. By default the results of each file are printed as soon as rg is done with it.
+    #[arg(long)]
+    buffered: bool,
+    /// When to color the output: auto colors when stdout is a terminal and `NO_COLOR` is not set
+    /// [default: auto]
+    #[arg(long, value_enum)]
+    color: Option<ColorMode>,
+    /// Print statistics of the search (matches, files, bytes searched and time taken)
+    #[arg(long)]
+    stats: bool,
+    /// Give each submatch of a line its own selection index, so vg can open the editor at any
+    /// occurrence of the pattern in the line
+    #[arg(long)]
+    per_submatch: bool,
+    /// List the previous searches, the number of a search is given to `vg --search`
+    #[arg(long)]
+    history: bool,
+    /// Print the results of the last search again, without running rg. The context lines are
+    /// not kept in the match file.
+    #[arg(long)]
+    last: bool,
+    /// With `--last`, only print the matches of the files matching the glob, e.g. `*.rs`
+    #[arg(long, requires = "last")]
+    file: Option<String>,
+    /// With `--last`, only print the matches of the inclusive range of indexes, e.g. `20-40`
+    #[arg(long, requires = "last")]
+    range: Option<String>,
 
     /// Arguments for rg command. rg needs to be installed and in your PATH for cg to be able to find it.
     ///
     /// Example `cg find_text .` -> Look for find_text in the current directory
+    ///
+    /// With `-` as only argument, cg reads the output of `rg --json` (or a compatible tool) from
+    /// stdin instead of running rg: `rg --json find_text | cg -`. The grep format
+    /// `path:line:text` is also supported: `git grep -n find_text | cg -`
     // trailing_var_arg tells clap to stop parsing and collecting
     // everything as if the user would have provided --
-    #[arg(trailing_var_arg = true, required = true)]
+    #[arg(trailing_var_arg = true, required_unless_present_any = ["history", "last"])]
     rg: Vec<String>,
 }
 
+/// Find the pattern in the arguments of rg: the value of the first `-e`/`--regexp`, or else the
+/// first positional argument. `None` if the patterns are read from a file.
+fn rg_pattern(args: &[String]) -> Option<String> {
+    // Options of rg taking a value
+    let short_value = "ABCEMTdefgjmrt";
+    let long_value = [
+        "after-context",
+        "before-context",
+        "color",
+        "colors",
+        "context",
+        "context-separator",
+        "dfa-size-limit",
+        "encoding",
+        "engine",
+        "field-context-separator",
+        "field-match-separator",
+        "file",
+        "glob",
+        "hostname-bin",
+        "hyperlink-format",
+        "iglob",
+        "ignore-file",
+        "max-columns",
+        "max-count",
+        "max-depth",
+        "max-filesize",
+        "path-separator",
+        "pre",
+        "pre-glob",
+        "regex-size-limit",
+        "regexp",
+        "replace",
+        "sort",
+        "sortr",
+        "threads",
+        "type",
+        "type-add",
+        "type-clear",
+        "type-not",
+    ];
+
+    let mut positional = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            return positional.or_else(|| args.next().cloned());
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+            if !long_value.contains(&name) {
+                continue;
+            }
+            let value = value.or_else(|| args.next().cloned());
+            match name {
+                "regexp" => return value,
+                "file" => return None,
+                _ => {}
+            }
+        } else if let Some(short) = arg.strip_prefix('-').filter(|s| !s.is_empty()) {
+            // Short options can be grouped, the value is the end of the group or the next argument
+            if let Some((i, c)) = short.char_indices().find(|(_, c)| short_value.contains(*c)) {
+                let rest = &short[i + 1..];
+                let value = match rest.is_empty() {
+                    true => args.next().cloned(),
+                    false => Some(rest.to_string()),
+                };
+                match c {
+                    'e' => return value,
+                    'f' => return None,
+                    _ => {}
+                }
+            }
+        } else if positional.is_none() {
+            positional = Some(arg.clone());
+        }
+    }
+
+    positional
+}
+
+/// Check that rg can be found and start it with the arguments of the configuration and of the
+/// command line.
+async fn spawn_rg(
+    rg_bin_path: &str,
+    config_args: &[String],
+    command_args: &[String],
+) -> Result<Child, String> {
+    let rg_bin_path = &find_executable(rg_bin_path)
+        .map_err(|e| format!("{e}, try to install rg or use `--rg-bin-path`"))?;
+
+    let version = std::process::Command::new(rg_bin_path)
+        .arg("--version")
+        .output()
+        .expect("could not run rg");
+
+    let re = Regex::new(r"ripgrep (\d+\.\d+.\d+)").unwrap();
+
+    let mut binding = version.stdout.lines();
+    let check_version = binding.next_line().await.unwrap().unwrap();
+    if !re.is_match(&check_version) {
+        eprintln!("Binary does not seem to be ripgrep: {check_version}");
+    }
+
+    debug!("{check_version:?}");
+    info!("rg version: {:?}", version);
+
+    // Log the command and its arguments
+    info!(
+        "Running command: {} {:?}",
+        rg_bin_path,
+        command_args.join(" ")
+    );
+
+    Command::new(rg_bin_path)
+        .arg("--json")
+        // Options starting with -- are hard to give on the command line, since they are forwarded
+        // to rg, they come from the configuration file
+        .args(config_args.iter())
+        .args(command_args)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to execute {rg_bin_path}: {e}"))
+}
+
+/// Print the search of the store, then the ones of the history, the newest first.
+fn print_history(
+    match_file: &str,
+    index_file: &str,
+    history_dir: &str,
+    theme: &Theme,
+) -> Result<(), CgVgError> {
+    let mut searches = vec![];
+    // A store without search information can still be opened by vg, it is listed too
+    if fs::metadata(match_file).is_ok() {
+        let store = Store::open(match_file, index_file)?;
+        searches.push((0, store.info().clone(), store.len()));
+    }
+    // The searches of the history are only read, one that cannot be read is skipped and the
+    // others keep their number
+    for (number, entry) in (1..).zip(previous_searches(history_dir, match_file)?) {
+        match Store::map(&entry.match_file, &entry.index_file) {
+            Ok(store) => searches.push((number, store.info().clone(), store.len())),
+            Err(e) => eprintln!(
+                "Skipping the search {number} of the history {}: {e}",
+                entry.match_file
+            ),
+        }
+    }
+
+    history_view(&searches, theme);
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> ExitCode {
     env_logger::init();
 
-    let size = terminal_size();
-    let terminal_size = size.unwrap().0;
+    // Default to 80 columns when stdout is not a terminal
+    let terminal_size = terminal_size().map_or(80, |(width, _)| width.0 as u32);
 
     let args = match Args::try_parse() {
         Ok(args) => args,
@@ -64,8 +282,21 @@ async fn main() -> ExitCode {
                     info!("error: {err:?}");
 
                     Args {
-                        match_file: DEFAULT_MATCH_FILE.to_string(),
-                        rg_bin_path: DEFAULT_RG.to_string(),
+                        match_file: None,
+                        index_file: None,
+                        scope: None,
+                        rg_bin_path: None,
+                        max_text_size: None,
+                        color: None,
+                        input: None,
+                        view: None,
+                        buffered: false,
+                        stats: false,
+                        per_submatch: false,
+                        history: false,
+                        last: false,
+                        file: None,
+                        range: None,
                         rg: args.clone(),
                     }
                 }
@@ -75,108 +306,306 @@ async fn main() -> ExitCode {
 
     debug!("{:?}", args);
 
-    // Using `which` to check that the editor is in the path
-    let find = Command::new("which")
-        .arg(&args.rg_bin_path)
-        .output()
-        .await
-        .expect("failed to fin rg");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(1);
+        }
+    };
+    debug!("{:?}", config);
 
-    if find.status.code().unwrap() != 0 {
-        eprintln!(
-            "rg not found at path: {}, try to use install rg or `--rg-bin-path`",
-            args.rg_bin_path
-        );
-        return ExitCode::from(1);
+    let (match_file, index_file, history_dir) =
+        match config.store_paths(args.match_file, args.index_file, args.scope) {
+            Ok(paths) => paths,
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::from(1);
+            }
+        };
+
+    // Command line options take precedence over the configuration
+    let rg_bin_path = args.rg_bin_path.unwrap_or(config.cg.rg);
+    let max_text_size = args.max_text_size.unwrap_or(config.cg.max_text_size);
+    let max_text_size = (max_text_size > 0).then_some(&max_text_size);
+    let stats = args.stats || config.cg.stats;
+    let per_submatch = args.per_submatch || config.cg.per_submatch;
+    colored::control::set_override(use_colors(&args.color.unwrap_or(config.cg.color)));
+    let mut theme = match Theme::from_config(&config.colors) {
+        Ok(theme) => theme,
+        Err(e) => {
+            eprintln!("invalid configuration: {e}");
+            return ExitCode::from(1);
+        }
+    };
+    if let Ok(spec) = env::var(COLORS_ENV) {
+        theme = theme.with_env(&spec);
     }
 
-    let version = std::process::Command::new(&args.rg_bin_path)
-        .arg("--version")
-        .output()
-        .expect("could not run rg");
+    if args.history {
+        return match print_history(&match_file, &index_file, &history_dir, &theme) {
+            Ok(()) => ExitCode::from(0),
+            Err(e) => {
+                eprintln!("Cannot read the history {history_dir}: {e}");
+                ExitCode::from(1)
+            }
+        };
+    }
 
-    let re = Regex::new(r"ripgrep (\d+\.\d+.\d+)").unwrap();
+    let view_kind = args.view.unwrap_or(config.cg.view);
+    if args.last {
+        let filter = match (
+            args.file.as_deref().map(glob_regex).transpose(),
+            args.range.as_deref().map(parse_range).transpose(),
+        ) {
+            (Ok(glob), Ok(range)) => ReplayFilter { glob, range },
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("{e}");
+                return ExitCode::from(1);
+            }
+        };
 
-    let mut binding = version.stdout.lines();
-    let check_version = binding.next_line().await.unwrap().unwrap();
-    if !re.is_match(&check_version) {
-        eprintln!("Binary does not seem to be ripgrep: {check_version}");
+        // The results are printed like they were, whatever the options of this call
+        let store = Store::open(&match_file, &index_file);
+        let (info, entries) =
+            match store.and_then(|store| Ok((store.info().clone(), store.entries()?))) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    eprintln!("Cannot read the match file {match_file}: {e}");
+                    return ExitCode::from(1);
+                }
+            };
+        let mut view = make_view(
+            &view_kind,
+            args.buffered,
+            &terminal_size,
+            max_text_size,
+            &theme,
+            info.per_submatch,
+        );
+        for (record, idx) in replay_records(&entries, info.per_submatch, &filter) {
+            view.push(record, idx);
+        }
+        view.finish();
+
+        return ExitCode::from(0);
     }
 
-    debug!("{check_version:?}");
-    info!("rg version: {:?}", version);
+    // `cg -` reads the records from stdin instead of running rg, e.g. `rg --json foo | cg -`
+    let (mut cmd, input): (Option<Child>, Box<dyn AsyncRead + Unpin>) = if args.rg == ["-"] {
+        info!("Reading records from stdin");
+        (None, Box::new(tokio::io::stdin()))
+    } else {
+        let mut cmd = match spawn_rg(&rg_bin_path, &config.cg.rg_args, &args.rg).await {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::from(1);
+            }
+        };
 
-    // The first argument is the command, and the rest are its arguments
-    let command_args = &args.rg;
-    // Log the command and its arguments
-    info!(
-        "Running command: {} {:?}",
-        args.rg_bin_path,
-        command_args.join(" ")
-    );
+        // Ensure we have a handle to stdout
+        let stdout = cmd.stdout.take().expect("Failed to open stdout");
+        (Some(cmd), Box::new(stdout))
+    };
 
-    let mut cmd = Command::new(args.rg_bin_path)
-        .arg("--json")
-        // Make it configurable with a env variable since parsing
-        // from command line is tricky (due to the fact that we need
-        // to pass options starting with --) I think that the best would be either a configuration
-        // file or through env vars
-        .args(DEFAULT_RG_ARGS.iter())
-        .args(command_args)
-        .stdout(std::process::Stdio::piped())
-        .spawn()
-        .expect("failed to execute process");
+    // Use a buffered reader to read the lines asynchronously, as bytes since grep-like input
+    // might not be valid UTF-8
+    let mut reader = BufReader::new(input);
+    let mut buffer = vec![];
 
-    // Ensure we have a handle to stdout
-    let stdout = cmd.stdout.take().expect("Failed to open stdout");
+    // The previous search is kept in the history
+    if let Err(e) = archive_search(&history_dir, &match_file, &index_file, config.history_size) {
+        warn!("cannot archive the previous search in {history_dir}: {e}");
+    }
 
-    // Use a buffered reader to read the lines asynchronously
-    let mut reader = BufReader::new(stdout).lines();
+    // The store is written while rg runs, so an interrupted search keeps the results seen so far
+    let rg_args = match cmd {
+        Some(_) => [config.cg.rg_args.clone(), args.rg.clone()].concat(),
+        None => vec![],
+    };
+    let info = SearchInfo {
+        pattern: rg_pattern(&rg_args),
+        cwd: env::current_dir()
+            .ok()
+            .map(|cwd| cwd.to_string_lossy().to_string()),
+        args: rg_args,
+        timestamp: time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64),
+        per_submatch,
+    };
+    debug!("{info:?}");
+    // The directory of a scope is created with its first search
+    for file in [&match_file, &index_file] {
+        if let Some(dir) = path::Path::new(file).parent() {
+            fs::create_dir_all(dir).ok();
+        }
+    }
+    let mut store = match StoreWriter::create(&match_file, &index_file, &info) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Cannot write the match file {match_file}: {e}");
+            return ExitCode::from(1);
+        }
+    };
 
     let mut idx = 0;
-    let mut file_and_line: Vec<Index> = vec![];
     debug!("terminal size= {:?}", terminal_size);
 
-    let mut matches = vec![];
+    let mut view = make_view(
+        &view_kind,
+        args.buffered,
+        &terminal_size,
+        max_text_size,
+        &theme,
+        per_submatch,
+    );
+    let mut summary = None;
 
-    while let Some(line) = reader.next_line().await.expect("Failed to read line") {
-        debug!("Received line: {}", line);
-        debug!("{:?}", serde_json::from_str::<Match>(&line));
+    let input_format = match cmd {
+        Some(_) => InputFormat::RgJson,
+        None => args.input.unwrap_or_default(),
+    };
+    let mut parser = make_parser(&input_format);
 
-        let matched = match serde_json::from_str::<Match>(&line) {
-            Ok(parsed) => parsed,
+    loop {
+        buffer.clear();
+        let line = match reader.read_until(b'\n', &mut buffer).await {
+            Ok(0) => None,
+            Ok(_) => {
+                let line = buffer.strip_suffix(b"\n").unwrap_or(&buffer);
+                Some(line.strip_suffix(b"\r").unwrap_or(line))
+            }
             Err(e) => {
-                warn!("TODO: {e:?}");
-                eprintln!("Received record from rg with unsopported format: {}", line);
+                eprintln!("Failed to read line: {e}");
                 return ExitCode::from(1);
             }
         };
+        debug!("Received line: {:?}", line.map(String::from_utf8_lossy));
 
-        matches.push((matched.clone(), idx));
-
-        match matched {
-            Match::Match {
-                ref path,
-                lines: _,
-                line_number,
-                absolute_offset: _,
-                submatches: _,
-            } => {
-                file_and_line.push((path.text.to_string(), line_number));
-                idx += 1;
-            }
-            _ => {}
+        let records = match line {
+            Some(line) => match parser.parse_line(line) {
+                Ok(records) => records,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return ExitCode::from(1);
+                }
+            },
+            None => parser.finish(),
         };
+
+        for matched in records {
+            let record_idx = idx;
+
+            match matched {
+                Match::Match {
+                    ref path,
+                    ref lines,
+                    line_number,
+                    ref submatches,
+                    ..
+                } => {
+                    // All the submatches are kept so `cg --last` can print the line again
+                    let mut line_ranges: Vec<(u32, u32)> =
+                        submatches.iter().map(|s| (s.start, s.end)).collect();
+                    // vg opens the editor at the first submatch of the line, or at each submatch
+                    let ranges = match submatches.first() {
+                        Some(_) if per_submatch => submatches
+                            .iter()
+                            .map(|s| (s.start as usize, s.end as usize))
+                            .collect(),
+                        Some(first) => vec![(first.start as usize, first.end as usize)],
+                        None => vec![(0, 0)],
+                    };
+                    let line_entry = idx;
+                    for (start, end) in ranges {
+                        let index = Index::with_submatch(
+                            path.as_bytes().to_vec(),
+                            line_number,
+                            lines.as_bytes(),
+                            start,
+                            end,
+                        );
+                        // Only the first entry of the line keeps its text and its ranges, the
+                        // next submatches point to it
+                        let index = match idx == line_entry {
+                            true => Index {
+                                ranges: mem::take(&mut line_ranges),
+                                ..index
+                            },
+                            false => Index {
+                                text: vec![],
+                                ranges: vec![],
+                                line_entry: Some(line_entry),
+                                ..index
+                            },
+                        };
+                        store.push(&index).expect("cannot write the match file");
+                        idx += 1;
+                    }
+                }
+                Match::End { .. } => {
+                    store.flush().expect("cannot write the match file");
+                }
+                Match::Summary {
+                    ref elapsed_total,
+                    ref stats,
+                } => {
+                    summary = Some((elapsed_total.clone(), stats.clone()));
+                }
+                _ => {}
+            };
+
+            view.push(matched, record_idx);
+        }
+
+        if line.is_none() {
+            break;
+        }
     }
 
-    match_view(&matches, &(terminal_size.0 as u32), Some(&500));
+    view.finish();
+
+    if stats {
+        match summary {
+            Some((elapsed_total, stats)) => stats_view(&elapsed_total, &stats, &theme),
+            None => warn!("rg did not send a summary, no statistics to print"),
+        }
+    }
 
     // Ensure the command completes
-    let status = cmd.wait().await.expect("");
-    debug!("Command finished with status: {}", status);
+    if let Some(cmd) = cmd.as_mut() {
+        let status = cmd.wait().await.expect("");
+        debug!("Command finished with status: {}", status);
+    }
 
-    let match_file = expand_path(&args.match_file).unwrap();
-    save_text(file_and_line, &match_file);
+    store.finish().expect("cannot write the match file");
 
-    return ExitCode::from(0);
+    ExitCode::from(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn test_rg_pattern() {
+        let pattern = |args: &[&str]| rg_pattern(&strings(args));
+
+        assert_eq!(
+            Some("foo".into()),
+            pattern(&["--sort", "path", "foo", "src"])
+        );
+        assert_eq!(Some("foo".into()), pattern(&["-tpy", "-A", "2", "foo"]));
+        assert_eq!(Some("foo".into()), pattern(&["src", "-ie", "foo"]));
+        assert_eq!(Some("foo".into()), pattern(&["--regexp=foo", "src"]));
+        assert_eq!(Some("-foo".into()), pattern(&["-i", "--", "-foo"]));
+        assert_eq!(None, pattern(&["-f", "patterns.txt", "src"]));
+        assert_eq!(None, pattern(&["--files"]));
+    }
 }
@@ -8,8 +8,8 @@ where
     T: Into<u64> + Copy,
 {
     let num = (*number).into();
-    if num == 0 as u64 {
-        1 as u32
+    if num == 0 {
+        1
     } else {
         (num as f64).log10().floor() as u32 + 1
     }
@@ -45,19 +45,76 @@ pub fn pad_number(number: u32, max_size: u32) -> String {
 
     if nb_digits < max_size {
         let diff = max_size - nb_digits;
-        let padding = std::iter::repeat(" ")
-            .take(diff as usize)
-            .collect::<String>();
+        let padding = " ".repeat(diff as usize);
         format!("{}{}", number, padding)
     } else {
         format!("{}", number)
     }
 }
 
+/// Shorten `text` to `max_length` chars by replacing its middle with `…`, the beginning and the
+/// end of a path are usually the most meaningful parts.
+pub fn truncate_middle(text: &str, max_length: u32) -> String {
+    let length = text.chars().count();
+    let max_length = max_length as usize;
+
+    if length <= max_length {
+        return text.to_string();
+    }
+    if max_length == 0 {
+        return String::new();
+    }
+
+    let tail = (max_length - 1) / 2;
+    let head = max_length - 1 - tail;
+
+    let begin: String = text.chars().take(head).collect();
+    let end: String = text.chars().skip(length - tail).collect();
+
+    format!("{begin}…{end}")
+}
+
+/// Format a number of bytes with a binary unit suffix, e.g. `1.5 KiB`.
+pub fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Format a timestamp in milliseconds since the Unix epoch as a local date, e.g.
+/// `2024-03-01 14:05`.
+pub fn local_date(timestamp: u64) -> String {
+    let time = (timestamp / 1000) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return String::from("?");
+    }
+
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min
+    )
+}
+
 /// Wrap text with support for colored string.
 ///
 /// - replace tabs with a number of whitespace to ensure that the printed line stays in the
-/// delimited space.
+///   delimited space.
 /// - if `fill_end` is true, then empty spaces are added at the end of each wrapped line.
 pub fn wrap_text<'a>(
     text: &'a str,
@@ -70,14 +127,13 @@ pub fn wrap_text<'a>(
     let mut current_style: Vec<String> = vec![];
 
     iter_colored(text)
-        .map(move |c| {
+        .flat_map(move |c| {
             if c == "\t" {
-                return iter::repeat(" ".to_string()).take(tab_size as usize);
+                iter::repeat(" ".to_string()).take(tab_size as usize)
             } else {
-                return iter::repeat(c).take(1);
+                iter::repeat(c).take(1)
             }
         })
-        .flatten()
         .batching(move |it| {
             let (len, temp) = it
                 .fold_while((0, current_style.clone()), |(length, mut acc), c| {
@@ -118,16 +174,13 @@ pub fn wrap_text<'a>(
             if len == 0 {
                 None
             } else if fill_end && len < max_length {
-                let padding = std::iter::repeat(" ")
-                    .take((max_length - len) as usize)
-                    .collect::<String>();
+                let padding = " ".repeat((max_length - len) as usize);
 
                 Some(format!("{line}{padding}"))
             } else {
                 Some(line)
             }
         })
-        .map(|array| String::from(array))
 }
 
 #[cfg(test)]
@@ -138,6 +191,7 @@ mod tests {
 
     #[test]
     fn test_wrap_text() {
+        colored::control::set_override(true);
         let tab_size = 8;
         // Simple cases
         let res = wrap_text("1234567890abc", 5, tab_size, false).collect_vec();
@@ -147,7 +201,7 @@ mod tests {
         assert_eq!(vec!["1234567890abc"], res);
 
         // Got coloring
-        let blue = format!("aaaaabbbbbzzzzz").blue().to_string();
+        let blue = "aaaaabbbbbzzzzz".blue().to_string();
 
         let res = wrap_text(&blue, 5, tab_size, false).collect_vec();
         assert_eq!(
@@ -159,11 +213,7 @@ mod tests {
             res
         );
 
-        let blue_bold_underline = format!("aaaaabbbbbzzzzz")
-            .blue()
-            .bold()
-            .underline()
-            .to_string();
+        let blue_bold_underline = "aaaaabbbbbzzzzz".blue().bold().underline().to_string();
 
         let res = wrap_text(&blue_bold_underline, 5, tab_size, false).collect_vec();
 
@@ -192,19 +242,20 @@ mod tests {
             res
         );
 
-        let res = wrap_text(&"\taaaaaaaabbbbbbbb".to_string(), 8, tab_size, false).collect_vec();
+        let res = wrap_text("\taaaaaaaabbbbbbbb", 8, tab_size, false).collect_vec();
 
         println!("{res:?}");
 
-        println!("{}", "aaaaaaaabbbbbbbb");
-        println!("{}", "\taaaaaaaabbbbbbbb");
+        println!("aaaaaaaabbbbbbbb");
+        println!("\taaaaaaaabbbbbbbb");
 
         assert_eq!(vec!["        ", "aaaaaaaa", "bbbbbbbb"], res);
     }
 
     #[test]
     fn test_iter_colored() {
-        let blue_bold_underline = format!("abz").blue().bold().underline().to_string();
+        colored::control::set_override(true);
+        let blue_bold_underline = "abz".blue().bold().underline().to_string();
 
         assert_eq!(
             vec!["\u{1b}[1;4;34m", "a", "b", "z", "\u{1b}[0m"],
@@ -227,6 +278,30 @@ mod tests {
         assert_eq!(3, number_of_digits(&cinquante));
     }
 
+    #[test]
+    fn test_truncate_middle() {
+        assert_eq!("src/main.rs", truncate_middle("src/main.rs", 11));
+        assert_eq!("src/….rs", truncate_middle("src/main.rs", 8));
+        assert_eq!("s…", truncate_middle("src/main.rs", 2));
+        assert_eq!("", truncate_middle("src/main.rs", 0));
+    }
+
+    #[test]
+    fn test_local_date() {
+        // The hour depends on the time zone
+        let date = local_date(1_700_000_000_000);
+        assert!(date.starts_with("2023-11-1"), "{date}");
+        assert_eq!(16, date.len());
+    }
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!("0 B", human_bytes(0));
+        assert_eq!("1023 B", human_bytes(1023));
+        assert_eq!("1.5 KiB", human_bytes(1536));
+        assert_eq!("3.0 GiB", human_bytes(3 * 1024 * 1024 * 1024));
+    }
+
     #[test]
     #[should_panic(expected = "pad_number wrong arguments number of digits of 3 > 0")]
     fn test_pad_number_assert() {
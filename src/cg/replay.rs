@@ -0,0 +1,264 @@
+use crate::ripgrep_json::{Data, Match, SubMatch};
+use regex::Regex;
+use rgvg::common::Index;
+use std::ops;
+
+/// Filters of `cg --last`, the matches keep the index they had in the search.
+#[derive(Debug, Default)]
+pub struct ReplayFilter {
+    /// Only the files matching the glob
+    pub glob: Option<Regex>,
+    /// Only the indexes of this inclusive range
+    pub range: Option<(u32, u32)>,
+}
+
+impl ReplayFilter {
+    /// Whether to print the line of `index`, which has the indexes `indexes`.
+    fn keep(&self, index: &Index, indexes: ops::Range<u32>) -> bool {
+        let in_range = self.range.map_or(true, |(start, end)| {
+            start < indexes.end && indexes.start <= end
+        });
+        let in_glob = self.glob.as_ref().map_or(true, |glob| {
+            let path = String::from_utf8_lossy(&index.path);
+            glob.is_match(path.strip_prefix("./").unwrap_or(&path))
+        });
+
+        in_range && in_glob
+    }
+}
+
+/// Translate a glob to a regex matching the whole path.
+///
+/// `*` and `?` do not match `/`, `**` matches any number of directories. Like in rg, a glob
+/// without `/` is matched against the file name.
+pub fn glob_regex(glob: &str) -> Result<Regex, String> {
+    let mut regex = String::from(match glob.contains('/') {
+        true => "^",
+        false => "(^|/)",
+    });
+
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.next_if_eq(&'*').is_some() => match chars.next_if_eq(&'/') {
+                Some(_) => regex.push_str("(.*/)?"),
+                None => regex.push_str(".*"),
+            },
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+
+    Regex::new(&regex).map_err(|e| format!("Invalid glob {glob:?}: {e}"))
+}
+
+/// Parse an inclusive range of indexes like `20-40`, or a single index.
+pub fn parse_range(range: &str) -> Result<(u32, u32), String> {
+    let parse = |number: &str| {
+        number
+            .parse::<u32>()
+            .map_err(|_| format!("Invalid range {range:?}, expected a range like `20-40`."))
+    };
+
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse(start)?, parse(end)?),
+        None => (parse(range)?, parse(range)?),
+    };
+    match start <= end {
+        true => Ok((start, end)),
+        false => Err(format!(
+            "Invalid range {range:?}, {start} is greater than {end}."
+        )),
+    }
+}
+
+/// Records of rg built from the entries of the store, with their index, to be printed by the
+/// views like the ones of a search.
+///
+/// With `per_submatch`, the consecutive entries of a line are the submatches of one record.
+pub fn replay_records(
+    entries: &[Index],
+    per_submatch: bool,
+    filter: &ReplayFilter,
+) -> Vec<(Match, u32)> {
+    let mut records = vec![];
+    let mut last_path: Option<&[u8]> = None;
+    let mut idx = 0;
+
+    while let Some(index) = entries.get(idx) {
+        let first_idx = idx as u32;
+        idx += 1;
+        if per_submatch {
+            while entries
+                .get(idx)
+                .is_some_and(|next| next.path == index.path && next.line == index.line)
+            {
+                idx += 1;
+            }
+        }
+
+        if !filter.keep(index, first_idx..idx as u32) {
+            continue;
+        }
+
+        if last_path != Some(&index.path) {
+            if let Some(path) = last_path {
+                records.push((end_record(path), first_idx));
+            }
+            records.push((
+                Match::Begin {
+                    path: Data::from_bytes(index.path.clone()),
+                },
+                first_idx,
+            ));
+            last_path = Some(&index.path);
+        }
+
+        let submatches = index
+            .ranges
+            .iter()
+            .filter(|(start, end)| start < end && *end as usize <= index.text.len())
+            .map(|(start, end)| SubMatch {
+                submatch: Data::from_bytes(index.text[*start as usize..*end as usize].to_vec()),
+                start: *start,
+                end: *end,
+            })
+            .collect();
+        let mut lines = index.text.clone();
+        lines.push(b'\n');
+
+        records.push((
+            Match::Match {
+                path: Data::from_bytes(index.path.clone()),
+                lines: Data::from_bytes(lines),
+                line_number: index.line,
+                absolute_offset: 0,
+                submatches,
+            },
+            first_idx,
+        ));
+    }
+
+    if let Some(path) = last_path {
+        records.push((end_record(path), idx as u32));
+    }
+
+    records
+}
+
+fn end_record(path: &[u8]) -> Match {
+    Match::End {
+        path: Data::from_bytes(path.to_vec()),
+        binary_offset: None,
+        stats: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_regex() {
+        let glob = glob_regex("*.rs").unwrap();
+        assert!(glob.is_match("src/main.rs"));
+        assert!(glob.is_match("main.rs"));
+        assert!(!glob.is_match("src/main.rsx"));
+
+        let glob = glob_regex("src/*.rs").unwrap();
+        assert!(glob.is_match("src/main.rs"));
+        assert!(!glob.is_match("src/cg/main.rs"));
+
+        let glob = glob_regex("src/**/*.rs").unwrap();
+        assert!(glob.is_match("src/main.rs"));
+        assert!(glob.is_match("src/cg/main.rs"));
+
+        assert!(glob_regex("a?.c").unwrap().is_match("ab.c"));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(Ok((20, 40)), parse_range("20-40"));
+        assert_eq!(Ok((3, 3)), parse_range("3"));
+        assert!(parse_range("40-20").is_err());
+        assert!(parse_range("a-b").is_err());
+    }
+
+    #[test]
+    fn test_replay_records() {
+        let text = b"foo(bar, foo)";
+        let ranges = vec![(0, 3), (9, 12)];
+        let entries = vec![
+            Index {
+                ranges,
+                ..Index::with_submatch(b"a.rs".to_vec(), 1, text, 0, 3)
+            },
+            Index {
+                text: vec![],
+                ranges: vec![],
+                line_entry: Some(0),
+                ..Index::with_submatch(b"a.rs".to_vec(), 1, text, 9, 12)
+            },
+            Index::with_submatch(b"b.rs".to_vec(), 2, text, 0, 3),
+        ];
+
+        let kinds = |records: &[(Match, u32)]| -> Vec<(String, u32)> {
+            records
+                .iter()
+                .map(|(record, idx)| {
+                    let kind = match record {
+                        Match::Begin { path } => format!("begin {}", path.to_string_lossy()),
+                        Match::Match { submatches, .. } => format!("match {}", submatches.len()),
+                        Match::End { .. } => String::from("end"),
+                        _ => String::from("other"),
+                    };
+                    (kind, *idx)
+                })
+                .collect()
+        };
+
+        // The submatches of a line are grouped, the numbering of the search is kept
+        let records = replay_records(&entries, true, &ReplayFilter::default());
+        assert_eq!(
+            vec![
+                (String::from("begin a.rs"), 0),
+                (String::from("match 2"), 0),
+                (String::from("end"), 2),
+                (String::from("begin b.rs"), 2),
+                (String::from("match 1"), 2),
+                (String::from("end"), 3),
+            ],
+            kinds(&records)
+        );
+
+        let filter = ReplayFilter {
+            glob: Some(glob_regex("b.*").unwrap()),
+            range: None,
+        };
+        let records = replay_records(&entries, true, &filter);
+        assert_eq!(
+            vec![
+                (String::from("begin b.rs"), 2),
+                (String::from("match 1"), 2),
+                (String::from("end"), 3),
+            ],
+            kinds(&records)
+        );
+
+        let filter = ReplayFilter {
+            glob: None,
+            range: Some((1, 1)),
+        };
+        let records = replay_records(&entries, true, &filter);
+        assert_eq!(
+            vec![
+                (String::from("begin a.rs"), 0),
+                (String::from("match 2"), 0),
+                (String::from("end"), 3),
+            ],
+            kinds(&records)
+        );
+    }
+}
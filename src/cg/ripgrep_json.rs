@@ -1,46 +1,183 @@
-use serde::{Deserialize, Serialize};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::*;
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct Text {
-    pub text: String,
+/// Arbitrary data sent by rg for paths and lines.
+///
+/// rg sends `{"text": ...}` when the data is valid UTF-8, and `{"bytes": <base64>}` otherwise.
+/// The bytes are decoded when the record is parsed.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Data {
+    Text {
+        text: String,
+    },
+    Bytes {
+        #[serde(serialize_with = "encode_base64", deserialize_with = "decode_base64")]
+        bytes: Vec<u8>,
+    },
+}
+
+impl Data {
+    /// Raw bytes of the data.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Data::Text { text } => text.as_bytes(),
+            Data::Bytes { bytes } => bytes,
+        }
+    }
+
+    /// Data of raw bytes, as text when they are valid UTF-8 like rg does.
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(text) => Data::Text { text },
+            Err(e) => Data::Bytes {
+                bytes: e.into_bytes(),
+            },
+        }
+    }
+
+    /// Text of the data, invalid UTF-8 sequences are replaced with `U+FFFD`.
+    pub fn to_string_lossy(&self) -> String {
+        match self {
+            Data::Text { text } => text.clone(),
+            Data::Bytes { bytes } => String::from_utf8_lossy(bytes).to_string(),
+        }
+    }
+}
+
+fn encode_base64<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&BASE64_STANDARD.encode(bytes))
+}
+
+fn decode_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    BASE64_STANDARD
+        .decode(encoded)
+        .map_err(serde::de::Error::custom)
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct SubMatch {
     #[serde(rename = "match")]
-    pub submatch: Text,
+    pub submatch: Data,
     pub start: u32,
     pub end: u32,
 }
 
-//TODO: Missing fields
+/// Duration as sent by rg.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Elapsed {
+    pub secs: u64,
+    pub nanos: u32,
+    /// Human readable version of the duration, e.g. `0.001234s`
+    pub human: String,
+}
+
+impl Elapsed {
+    pub fn as_duration(&self) -> time::Duration {
+        time::Duration::new(self.secs, self.nanos)
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Stats {
-    pub matched_lines: u32,
-    pub matches: u32,
+    pub elapsed: Elapsed,
     pub searches: u32,
     pub searches_with_match: u32,
+    pub bytes_searched: u64,
+    pub bytes_printed: u64,
+    pub matched_lines: u32,
+    pub matches: u32,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type", content = "data")]
+#[allow(clippy::enum_variant_names)]
 pub enum Match {
     Begin {
-        path: Text,
+        path: Data,
     },
     Match {
-        path: Text,
-        lines: Text,
+        path: Data,
+        lines: Data,
+        line_number: u32,
+        absolute_offset: u32,
+        submatches: Vec<SubMatch>,
+    },
+    /// Lines around a match, emitted by rg with the options `-A`, `-B` or `-C`.
+    Context {
+        path: Data,
+        lines: Data,
         line_number: u32,
         absolute_offset: u32,
         submatches: Vec<SubMatch>,
     },
     End {
-        path: Text,
+        path: Data,
+        /// Offset of the first NUL byte when rg stopped searching a binary file.
+        binary_offset: Option<u64>,
+        stats: Option<Stats>,
     },
     Summary {
+        elapsed_total: Elapsed,
         stats: Stats,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_context() {
+        let record = r#"{"type":"context","data":{"path":{"text":"src/a.rs"},"lines":{"text":"fn a() {\n"},"line_number":1,"absolute_offset":0,"submatches":[]}}"#;
+
+        match serde_json::from_str::<Match>(record).unwrap() {
+            Match::Context {
+                path, line_number, ..
+            } => {
+                assert_eq!(b"src/a.rs", path.as_bytes());
+                assert_eq!(1, line_number);
+            }
+            m => panic!("expected a context record, got {m:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        // "caf\xe9.txt", a Latin-1 path that is not valid UTF-8
+        let record = r#"{"type":"begin","data":{"path":{"bytes":"Y2Fm6S50eHQ="}}}"#;
+
+        match serde_json::from_str::<Match>(record).unwrap() {
+            Match::Begin { path } => {
+                assert_eq!(b"caf\xe9.txt", path.as_bytes());
+                assert_eq!("caf\u{fffd}.txt", path.to_string_lossy());
+            }
+            m => panic!("expected a begin record, got {m:?}"),
+        }
+
+        let record = r#"{"type":"begin","data":{"path":{"bytes":"not base64!"}}}"#;
+        assert!(serde_json::from_str::<Match>(record).is_err());
+    }
+
+    #[test]
+    fn test_parse_summary() {
+        let record = r#"{"data":{"elapsed_total":{"human":"0.002s","nanos":2000000,"secs":0},"stats":{"bytes_printed":1200,"bytes_searched":124,"elapsed":{"human":"0.0001s","nanos":100000,"secs":0},"matched_lines":3,"matches":4,"searches":2,"searches_with_match":2}},"type":"summary"}"#;
+
+        match serde_json::from_str::<Match>(record).unwrap() {
+            Match::Summary {
+                elapsed_total,
+                stats,
+            } => {
+                assert_eq!(time::Duration::from_millis(2), elapsed_total.as_duration());
+                assert_eq!(124, stats.bytes_searched);
+                assert_eq!(1200, stats.bytes_printed);
+                assert_eq!(4, stats.matches);
+                assert_eq!(2, stats.searches_with_match);
+            }
+            m => panic!("expected a summary record, got {m:?}"),
+        }
+    }
+}
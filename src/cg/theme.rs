@@ -0,0 +1,342 @@
+use colored::{Color, Colorize};
+use rgvg::config::{ColorMode, ColorsConfig};
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::*;
+
+/// Environment variable to override the theme, with the `LS_COLORS` syntax:
+/// `path=31:index=36:match=1;34`.
+pub static COLORS_ENV: &str = "CG_COLORS";
+
+/// Style of one role of the theme: a foreground and a background color and text attributes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub dimmed: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl Style {
+    /// Parse a style from words, e.g. `"bold bright blue"`. The color names are the ones of the
+    /// `colored` crate, `"none"` is a style without any attribute.
+    pub fn parse(spec: &str) -> Result<Style, String> {
+        let mut style = Style::default();
+        let mut color = vec![];
+
+        for word in spec.split_whitespace() {
+            match word {
+                "bold" => style.bold = true,
+                "dimmed" | "dim" => style.dimmed = true,
+                "italic" => style.italic = true,
+                "underline" => style.underline = true,
+                "none" => {}
+                _ => color.push(word),
+            }
+        }
+
+        if !color.is_empty() {
+            let name = color.join(" ");
+            style.fg = Some(
+                Color::from_str(&name)
+                    .map_err(|_| format!("unknown color {name:?} in {spec:?}"))?,
+            );
+        }
+
+        Ok(style)
+    }
+
+    /// Parse a style from SGR codes as used by `LS_COLORS`, e.g. `"1;34"`, `"38;5;208"` or
+    /// `"38;2;255;0;0;48;5;236"`.
+    pub fn from_sgr(codes: &str) -> Result<Style, String> {
+        let mut style = Style::default();
+        let invalid = || format!("invalid SGR sequence {codes:?}");
+        let mut codes_iter = codes.split(';').filter(|c| !c.is_empty()).map(|code| {
+            code.parse::<u8>()
+                .map_err(|_| format!("invalid SGR sequence {codes:?}"))
+        });
+
+        while let Some(code) = codes_iter.next() {
+            match code? {
+                0 => style = Style::default(),
+                1 => style.bold = true,
+                2 => style.dimmed = true,
+                3 => style.italic = true,
+                4 => style.underline = true,
+                code @ (30..=37 | 90..=97) => style.fg = Some(sgr_color(code)),
+                code @ (40..=47 | 100..=107) => style.bg = Some(sgr_color(code - 10)),
+                38 => style.fg = Some(extended_color(&mut codes_iter).ok_or_else(invalid)??),
+                48 => style.bg = Some(extended_color(&mut codes_iter).ok_or_else(invalid)??),
+                39 => style.fg = None,
+                49 => style.bg = None,
+                _ => return Err(invalid()),
+            };
+        }
+
+        Ok(style)
+    }
+
+    /// Apply the style to `text`.
+    pub fn paint(&self, text: &str) -> String {
+        let mut colored = text.normal();
+        if let Some(fg) = self.fg {
+            colored = colored.color(fg);
+        }
+        if let Some(bg) = self.bg {
+            colored = colored.on_color(bg);
+        }
+        if self.bold {
+            colored = colored.bold();
+        }
+        if self.dimmed {
+            colored = colored.dimmed();
+        }
+        if self.italic {
+            colored = colored.italic();
+        }
+        if self.underline {
+            colored = colored.underline();
+        }
+
+        colored.to_string()
+    }
+}
+
+/// Foreground color of the SGR codes 30-37 and 90-97.
+fn sgr_color(code: u8) -> Color {
+    let colors = [
+        Color::Black,
+        Color::Red,
+        Color::Green,
+        Color::Yellow,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::White,
+    ];
+    let bright = [
+        Color::BrightBlack,
+        Color::BrightRed,
+        Color::BrightGreen,
+        Color::BrightYellow,
+        Color::BrightBlue,
+        Color::BrightMagenta,
+        Color::BrightCyan,
+        Color::BrightWhite,
+    ];
+
+    match code {
+        30..=37 => colors[(code - 30) as usize],
+        _ => bright[(code - 90) as usize],
+    }
+}
+
+/// Color following the SGR codes 38 and 48: `5;N` for the 256 colors palette, `2;R;G;B` for
+/// RGB. `None` if the codes are missing or not supported.
+///
+/// `colored` has no 256 colors variant, the colors of the palette beyond the 16 basic ones are
+/// converted to RGB, like xterm does.
+fn extended_color<I>(codes: &mut I) -> Option<Result<Color, String>>
+where
+    I: Iterator<Item = Result<u8, String>>,
+{
+    let color = match codes.next()? {
+        Ok(5) => match codes.next()? {
+            Ok(n @ 0..=7) => sgr_color(n + 30),
+            Ok(n @ 8..=15) => sgr_color(n - 8 + 90),
+            Ok(n @ 16..=231) => {
+                let level = |c: u8| if c == 0 { 0 } else { 55 + 40 * c };
+                let n = n - 16;
+                Color::TrueColor {
+                    r: level(n / 36),
+                    g: level(n / 6 % 6),
+                    b: level(n % 6),
+                }
+            }
+            Ok(n) => {
+                let gray = 8 + 10 * (n - 232);
+                Color::TrueColor {
+                    r: gray,
+                    g: gray,
+                    b: gray,
+                }
+            }
+            Err(e) => return Some(Err(e)),
+        },
+        Ok(2) => {
+            let mut next = || codes.next();
+            match (next()?, next()?, next()?) {
+                (Ok(r), Ok(g), Ok(b)) => Color::TrueColor { r, g, b },
+                (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return Some(Err(e)),
+            }
+        }
+        Ok(_) => return None,
+        Err(e) => return Some(Err(e)),
+    };
+
+    Some(Ok(color))
+}
+
+/// Styles of the elements of the match view.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Theme {
+    pub path: Style,
+    pub index: Style,
+    pub line_number: Style,
+    pub submatch: Style,
+    /// Context lines and separators between non-contiguous blocks
+    pub context: Style,
+    /// Notice printed instead of the lines that are too long
+    pub truncated: Style,
+}
+
+impl Theme {
+    /// Read the theme from the configuration.
+    pub fn from_config(config: &ColorsConfig) -> Result<Self, String> {
+        Ok(Theme {
+            path: Style::parse(&config.path)?,
+            index: Style::parse(&config.index)?,
+            line_number: Style::parse(&config.line_number)?,
+            submatch: Style::parse(&config.submatch)?,
+            context: Style::parse(&config.context)?,
+            truncated: Style::parse(&config.truncated)?,
+        })
+    }
+
+    /// Override the roles listed in `spec`, a `LS_COLORS` like list of `role=SGR` separated by
+    /// `:`. Invalid entries are ignored, with an error printed on stderr.
+    pub fn with_env(mut self, spec: &str) -> Self {
+        for entry in spec.split(':').filter(|e| !e.is_empty()) {
+            let Some((role, codes)) = entry.split_once('=') else {
+                eprintln!("{COLORS_ENV}: ignoring entry without `=`: {entry:?}");
+                continue;
+            };
+
+            let style = match Style::from_sgr(codes) {
+                Ok(style) => style,
+                Err(e) => {
+                    eprintln!("{COLORS_ENV}: ignoring {role:?}: {e}");
+                    continue;
+                }
+            };
+
+            match role {
+                "path" => self.path = style,
+                "index" => self.index = style,
+                "line_number" => self.line_number = style,
+                "match" => self.submatch = style,
+                "context" => self.context = style,
+                "truncated" => self.truncated = style,
+                _ => eprintln!("{COLORS_ENV}: ignoring unknown role {role:?}"),
+            }
+        }
+
+        self
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::from_config(&ColorsConfig::default()).expect("default theme")
+    }
+}
+
+/// Decide if the output should be colored.
+///
+/// In `auto` mode colors are disabled when `NO_COLOR` is set or when stdout is not a terminal.
+pub fn use_colors(mode: &ColorMode) -> bool {
+    match mode {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => {
+            env::var_os("NO_COLOR").map_or(true, |v| v.is_empty()) && io::stdout().is_terminal()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_style() {
+        assert_eq!(
+            Style {
+                fg: Some(Color::BrightBlue),
+                bold: true,
+                ..Style::default()
+            },
+            Style::parse("bold bright blue").unwrap()
+        );
+        assert_eq!(Style::default(), Style::parse("none").unwrap());
+        assert!(Style::parse("bold blurple").is_err());
+    }
+
+    #[test]
+    fn test_style_from_sgr() {
+        assert_eq!(
+            Style {
+                fg: Some(Color::Blue),
+                bold: true,
+                ..Style::default()
+            },
+            Style::from_sgr("01;34").unwrap()
+        );
+        assert_eq!(
+            Style {
+                fg: Some(Color::TrueColor {
+                    r: 255,
+                    g: 0,
+                    b: 10
+                }),
+                ..Style::default()
+            },
+            Style::from_sgr("38;2;255;0;10").unwrap()
+        );
+        assert_eq!(
+            Style {
+                fg: Some(Color::TrueColor {
+                    r: 255,
+                    g: 135,
+                    b: 0
+                }),
+                bg: Some(Color::Blue),
+                ..Style::default()
+            },
+            Style::from_sgr("38;5;208;44").unwrap()
+        );
+        assert_eq!(
+            Some(Color::BrightRed),
+            Style::from_sgr("38;5;9").unwrap().fg
+        );
+        assert_eq!(
+            Some(Color::TrueColor {
+                r: 48,
+                g: 48,
+                b: 48
+            }),
+            Style::from_sgr("48;5;236").unwrap().bg
+        );
+        assert!(Style::from_sgr("38;2;255").is_err());
+        assert!(Style::from_sgr("38;5").is_err());
+        assert!(Style::from_sgr("38;7;1").is_err());
+        assert!(Style::from_sgr("bold").is_err());
+    }
+
+    #[test]
+    fn test_theme_with_env() {
+        let theme = Theme::default().with_env("path=32:match=4:unknown=1:index");
+
+        assert_eq!(Some(Color::Green), theme.path.fg);
+        assert_eq!(
+            Style {
+                underline: true,
+                ..Style::default()
+            },
+            theme.submatch
+        );
+        assert_eq!(Theme::default().index, theme.index);
+    }
+}
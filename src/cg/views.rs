@@ -1,15 +1,19 @@
-use colored::Colorize;
-
-use crate::ripgrep_json::Match;
-use crate::{number_of_digits, pad_number, wrap_text};
+use crate::ripgrep_json::{Data, Elapsed, Match, Stats, SubMatch};
+use crate::theme::{Style, Theme};
+use crate::{human_bytes, local_date, number_of_digits, pad_number, truncate_middle, wrap_text};
+use rgvg::common::SearchInfo;
+use rgvg::config::ViewKind;
 
 /// Add padding and wrap text to fit the terminal_size.
 /// Return an iterator over strings with the lines wrapped and padded
+///
+/// When `idx` is `None` (for context lines) the index column is left blank.
 pub fn padding_and_wrap<'a>(
-    colored_text: &'a String,
+    colored_text: &'a str,
     line_number: &'a u32,
-    idx: &'a u32,
+    idx: Option<&'a u32>,
     terminal_size: &'a u32,
+    theme: &'a Theme,
     line_number_max: Option<u32>,
     idx_max: Option<u32>,
 ) -> impl Iterator<Item = String> + 'a {
@@ -17,23 +21,28 @@ pub fn padding_and_wrap<'a>(
         *line_number,
         number_of_digits(&line_number_max.unwrap_or(*line_number)),
     );
-    let idx_str = pad_number(*idx, number_of_digits(&idx_max.unwrap_or(*idx)));
+    let idx_str = match idx {
+        Some(idx) => pad_number(*idx, number_of_digits(&idx_max.unwrap_or(*idx))),
+        None => " ".repeat(number_of_digits(&idx_max.unwrap_or(0)) as usize),
+    };
 
     let prefix = format!(
         "{}    {}    ",
-        idx_str.cyan(),
-        line_number_str.bright_purple()
+        theme.index.paint(&idx_str),
+        theme.line_number.paint(&line_number_str)
     );
 
     let prefix_size = (line_number_str.len() + idx_str.len()) as u32 + 8;
 
-    let padding = std::iter::repeat(" ")
-        .take((prefix_size - 1) as usize)
-        .collect::<String>();
+    let padding = " ".repeat((prefix_size - 1) as usize);
 
     let text_size = terminal_size - prefix_size;
 
-    wrap_text(&colored_text, text_size, 8, true).enumerate().map(move |(line, s)| {
+    // An empty line still has its index and its line number printed
+    let mut wrapped = wrap_text(colored_text, text_size, 8, true).peekable();
+    let empty = wrapped.peek().is_none().then(String::new);
+
+    wrapped.chain(empty).enumerate().map(move |(line, s)| {
         if line == 0 {
             format!("{prefix}{s}")
         } else {
@@ -43,7 +52,7 @@ pub fn padding_and_wrap<'a>(
 }
 
 /// Color subranges of a string
-pub fn color_submatch(text: &String, submatches: &Vec<(u32, u32)>) -> Option<String> {
+pub fn color_submatch(text: &str, submatches: &[(u32, u32)], style: &Style) -> Option<String> {
     let mut color_submatches = String::from("");
     let mut cursor = 0;
 
@@ -55,7 +64,7 @@ pub fn color_submatch(text: &String, submatches: &Vec<(u32, u32)>) -> Option<Str
         );
 
         let begin = String::from(&text[(cursor as usize)..(*start as usize)]);
-        let submatch_str = format!("{}", text[(*start as usize)..(*end as usize)].blue().bold());
+        let submatch_str = style.paint(&text[(*start as usize)..(*end as usize)]);
 
         cursor = *end;
 
@@ -72,18 +81,63 @@ pub fn color_submatch(text: &String, submatches: &Vec<(u32, u32)>) -> Option<Str
     Some(result)
 }
 
-/// Function that prints a record from rigpgrep in the terminal.
-///
-/// The records will be treated with the following steps:
-/// - First it loops from all the records to find the max line and index value. That is required to
-/// know the padding size of the final display lines.
-/// - Secondly for each record it prints what needs to be displayed:
-///     - For the begin and end it prints the matched file and a line break.
-///     - For each individual match it wraps, padds and colors the text.
+/// Insert a marker with the selection index before each submatch, starting at `first_idx`.
+/// Return the text with the markers and the shifted submatch offsets.
+pub fn mark_submatches(
+    text: &str,
+    submatches: &[(u32, u32)],
+    first_idx: u32,
+    style: &Style,
+) -> (String, Vec<(u32, u32)>) {
+    let mut marked = String::new();
+    let mut offsets = vec![];
+    let mut cursor = 0;
+
+    for (i, (start, end)) in submatches.iter().enumerate() {
+        marked.push_str(&text[cursor..*start as usize]);
+        marked.push_str(&style.paint(&format!("[{}]", first_idx + i as u32)));
+        let shift = marked.len() as u32 - start;
+        offsets.push((start + shift, end + shift));
+        marked.push_str(&text[*start as usize..*end as usize]);
+        cursor = *end as usize;
+    }
+    marked.push_str(&text[cursor..]);
+
+    (marked, offsets)
+}
+
+/// Convert the bytes of a line to a string for the terminal, and shift the submatch offsets when
+/// invalid UTF-8 sequences are replaced.
 ///
-/// `max_text_size` enables to not displayed matched text that will be too large to display, and
-/// that will be cumbersome to read for the user.
-pub fn match_view(matched: &Vec<(Match, u32)>, terminal_size: &u32, max_text_size: Option<&u32>) {
+/// The trailing line break is removed.
+pub fn lossy_line(bytes: &[u8], submatches: &[(u32, u32)]) -> (String, Vec<(u32, u32)>) {
+    let mut text = String::new();
+    let mut offsets = vec![];
+    let mut cursor = 0;
+
+    for (start, end) in submatches.iter() {
+        let (start, end) = (*start as usize, *end as usize);
+        text.push_str(&String::from_utf8_lossy(&bytes[cursor..start]));
+        let new_start = text.len() as u32;
+        text.push_str(&String::from_utf8_lossy(&bytes[start..end]));
+        offsets.push((new_start, text.len() as u32));
+        cursor = end;
+    }
+    text.push_str(&String::from_utf8_lossy(&bytes[cursor..]));
+
+    let trimmed = text.trim_end_matches('\n').len() as u32;
+    text.truncate(trimmed as usize);
+    let offsets = offsets
+        .into_iter()
+        .map(|(start, end)| (start.min(trimmed), end.min(trimmed)))
+        .collect();
+
+    (text, offsets)
+}
+
+/// Find the max index and line number of the records. That is required to know the padding size
+/// of the printed lines.
+fn max_idx_and_line(matched: &[(Match, u32)]) -> (u32, u32) {
     let (mut max_idx, mut max_line) = (0, 0);
     for (m, idx) in matched.iter() {
         match m {
@@ -91,70 +145,395 @@ pub fn match_view(matched: &Vec<(Match, u32)>, terminal_size: &u32, max_text_siz
                 max_idx = std::cmp::max(max_idx, *idx);
                 max_line = std::cmp::max(max_line, *line_number);
             }
+            Match::Context { line_number, .. } => {
+                max_line = std::cmp::max(max_line, *line_number);
+            }
             _ => {}
         };
     }
 
-    let mut i = matched.iter();
-    while let Some(m) = i.next() {
-        let (record, idx) = m;
-        match &record {
+    (max_idx, max_line)
+}
+
+/// Prints records from ripgrep in the terminal, the index and line number columns are padded to
+/// the width of `max_idx` and `max_line`.
+///
+/// - For the begin and end it prints the matched file and a line break.
+/// - For each individual match it wraps, padds and colors the text.
+/// - Context lines are dimmed and have no index. Like rg, a `--` separator is printed between
+///   blocks of lines that are not contiguous.
+///
+/// `max_text_size` enables to not displayed matched text that will be too large to display, and
+/// that will be cumbersome to read for the user.
+///
+/// With `per_submatch`, each submatch of a line has its own selection index, printed in a marker
+/// before the submatch when the line has several of them.
+pub struct RecordPrinter<'a> {
+    terminal_size: &'a u32,
+    max_text_size: Option<&'a u32>,
+    theme: &'a Theme,
+    per_submatch: bool,
+    pub max_idx: u32,
+    pub max_line: u32,
+    /// Last line printed for the current file, used to find non-contiguous blocks.
+    last_line: Option<u32>,
+}
+
+impl<'a> RecordPrinter<'a> {
+    pub fn new(
+        terminal_size: &'a u32,
+        max_text_size: Option<&'a u32>,
+        theme: &'a Theme,
+        per_submatch: bool,
+    ) -> Self {
+        RecordPrinter {
+            terminal_size,
+            max_text_size,
+            theme,
+            per_submatch,
+            max_idx: 0,
+            max_line: 0,
+            last_line: None,
+        }
+    }
+
+    /// Return the notice to print instead of the line when it is too long to be displayed.
+    fn truncated(&self, lines: &Data) -> Option<String> {
+        let size = lines.as_bytes().len();
+        self.max_text_size
+            .filter(|max| size > **max as usize)
+            .map(|max| format!("text truncated size({})>{}", size, max))
+    }
+
+    /// Print a `--` separator if `line_number` does not follow the last printed line.
+    fn separate(&mut self, line_number: u32) {
+        if self.last_line.is_some_and(|last| last + 1 != line_number) {
+            println!("{}", self.theme.context.paint("--"));
+        }
+        self.last_line = Some(line_number);
+    }
+
+    fn print_lines(&self, text: &str, line_number: &u32, idx: Option<&u32>) {
+        for line in padding_and_wrap(
+            text,
+            line_number,
+            idx,
+            self.terminal_size,
+            self.theme,
+            Some(self.max_line),
+            Some(self.max_idx),
+        ) {
+            println!("{line}");
+        }
+    }
+
+    /// Text of a matched line with the submatches colored, `idx` is the selection index of the
+    /// line.
+    fn colored_match(&self, lines: &Data, submatches: &[SubMatch], idx: u32) -> String {
+        // In case the string is too long, it is pointless to print it.
+        match self.truncated(lines) {
+            Some(notice) => self.theme.truncated.paint(&notice),
+            None => {
+                let (text, submatches) = lossy_line(
+                    lines.as_bytes(),
+                    &submatches
+                        .iter()
+                        .map(|s| (s.start, s.end))
+                        .collect::<Vec<_>>(),
+                );
+                let (text, submatches) = if self.per_submatch && submatches.len() > 1 {
+                    mark_submatches(&text, &submatches, idx, &self.theme.index)
+                } else {
+                    (text, submatches)
+                };
+                color_submatch(&text, &submatches, &self.theme.submatch).expect("colored match")
+            }
+        }
+    }
+
+    pub fn print(&mut self, record: &Match, idx: &u32) {
+        match record {
             Match::Match {
-                lines, submatches, ..
+                lines,
+                submatches,
+                line_number,
+                ..
             } => {
-                let colored_match =
-                    // In case the string is too long, it is pointless to print it.
-                    if max_text_size.is_some_and(|max| lines.text.len() > *max as usize) {
-                        Some(
-                            format!(
-                                "text truncated size({})>{}",
-                                lines.text.len(),
-                                max_text_size.unwrap()
-                            )
-                            .red()
-                            .to_string(),
-                        )
-                    } else {
-                        color_submatch(
-                            &String::from(lines.text.trim_end_matches('\n')),
-                            &submatches.iter().map(|s| (s.start, s.end)).collect(),
-                        )
-                    };
-
-                let line_number = match record {
-                    Match::Match { line_number, .. } => Some(line_number),
-                    _ => None,
-                };
+                self.separate(*line_number);
+                let colored_match = self.colored_match(lines, submatches, *idx);
+                self.print_lines(&colored_match, line_number, Some(idx));
+            }
+            Match::Context {
+                lines, line_number, ..
+            } => {
+                self.separate(*line_number);
 
-                let result = (line_number, colored_match);
-
-                let lines_to_print = match &result {
-                    (Some(line_number), Some(text)) => padding_and_wrap(
-                        &text,
-                        line_number,
-                        idx,
-                        terminal_size,
-                        Some(max_line),
-                        Some(max_idx),
-                    ),
-                    _ => panic!(),
+                let text = match self.truncated(lines) {
+                    Some(notice) => self.theme.truncated.paint(&notice),
+                    None => self
+                        .theme
+                        .context
+                        .paint(&lossy_line(lines.as_bytes(), &[]).0),
                 };
 
-                for line in lines_to_print {
-                    println!("{line}");
-                }
+                self.print_lines(&text, line_number, None);
             }
             Match::Begin { path } => {
-                println!("{}", path.text.red());
+                self.last_line = None;
+                println!("{}", self.theme.path.paint(&path.to_string_lossy()));
             }
             Match::End { .. } => {
-                println!("");
+                println!();
             }
             _ => {}
         };
     }
 }
 
+/// Function that prints all the records from rigpgrep in the terminal.
+///
+/// It first loops from all the records to find the max line and index value, so all the lines of
+/// the search are aligned. Then each record is printed with a [`RecordPrinter`].
+pub fn match_view(
+    matched: &[(Match, u32)],
+    terminal_size: &u32,
+    max_text_size: Option<&u32>,
+    theme: &Theme,
+    per_submatch: bool,
+) {
+    let mut printer = RecordPrinter::new(terminal_size, max_text_size, theme, per_submatch);
+    (printer.max_idx, printer.max_line) = max_idx_and_line(matched);
+
+    for (record, idx) in matched.iter() {
+        printer.print(record, idx);
+    }
+}
+
+/// A way to print the records of rg in the terminal.
+pub trait View {
+    /// Add a record to the view, `idx` is the selection index of the match.
+    fn push(&mut self, record: Match, idx: u32);
+    /// Print the records that are not printed yet.
+    fn finish(&mut self);
+}
+
+/// Build the view of the given kind.
+///
+/// `buffered` makes the grouped view wait for the end of the search, see [`match_view`].
+/// `per_submatch` marks the index of each submatch, see [`RecordPrinter`].
+pub fn make_view<'a>(
+    kind: &ViewKind,
+    buffered: bool,
+    terminal_size: &'a u32,
+    max_text_size: Option<&'a u32>,
+    theme: &'a Theme,
+    per_submatch: bool,
+) -> Box<dyn View + 'a> {
+    let printer = RecordPrinter::new(terminal_size, max_text_size, theme, per_submatch);
+    match kind {
+        ViewKind::Grouped if buffered => Box::new(BufferedView::new(printer)),
+        ViewKind::Grouped => Box::new(StreamView::new(printer)),
+        ViewKind::Columns => Box::new(ColumnsView::new(printer)),
+    }
+}
+
+/// View that prints the records while rg is still searching.
+///
+/// The records are buffered until the end of each file, so the width of the line number column
+/// fits the lines of the file. The width of the index column grows as needed.
+pub struct StreamView<'a> {
+    printer: RecordPrinter<'a>,
+    block: Vec<(Match, u32)>,
+}
+
+impl<'a> StreamView<'a> {
+    pub fn new(printer: RecordPrinter<'a>) -> Self {
+        StreamView {
+            printer,
+            block: vec![],
+        }
+    }
+}
+
+impl View for StreamView<'_> {
+    /// Add a record to the view, the block of the file is printed when its end is received.
+    fn push(&mut self, record: Match, idx: u32) {
+        let is_end = matches!(record, Match::End { .. });
+        self.block.push((record, idx));
+
+        if is_end {
+            self.finish();
+        }
+    }
+
+    /// Print the records received so far.
+    fn finish(&mut self) {
+        let (max_idx, max_line) = max_idx_and_line(&self.block);
+        self.printer.max_idx = std::cmp::max(self.printer.max_idx, max_idx);
+        self.printer.max_line = max_line;
+
+        for (record, idx) in self.block.drain(..) {
+            self.printer.print(&record, &idx);
+        }
+    }
+}
+
+/// View that waits for the end of the search and prints it with [`match_view`].
+pub struct BufferedView<'a> {
+    printer: RecordPrinter<'a>,
+    matched: Vec<(Match, u32)>,
+}
+
+impl<'a> BufferedView<'a> {
+    pub fn new(printer: RecordPrinter<'a>) -> Self {
+        BufferedView {
+            printer,
+            matched: vec![],
+        }
+    }
+}
+
+impl View for BufferedView<'_> {
+    fn push(&mut self, record: Match, idx: u32) {
+        self.matched.push((record, idx));
+    }
+
+    fn finish(&mut self) {
+        match_view(
+            &self.matched,
+            self.printer.terminal_size,
+            self.printer.max_text_size,
+            self.printer.theme,
+            self.printer.per_submatch,
+        );
+        self.matched.clear();
+    }
+}
+
+/// View of the original cgvg: one line per match with the index, path, line number and text in
+/// aligned columns.
+///
+/// The path column takes at most a third of the terminal, longer paths are shortened in the
+/// middle. The text is cut at the end of the terminal. Context lines are not printed.
+pub struct ColumnsView<'a> {
+    printer: RecordPrinter<'a>,
+    /// Index, path, line number and colored text of each match
+    rows: Vec<(u32, String, u32, String)>,
+}
+
+impl<'a> ColumnsView<'a> {
+    pub fn new(printer: RecordPrinter<'a>) -> Self {
+        ColumnsView {
+            printer,
+            rows: vec![],
+        }
+    }
+}
+
+impl View for ColumnsView<'_> {
+    fn push(&mut self, record: Match, idx: u32) {
+        if let Match::Match {
+            path,
+            lines,
+            line_number,
+            submatches,
+            ..
+        } = record
+        {
+            let text = self.printer.colored_match(&lines, &submatches, idx);
+            self.rows
+                .push((idx, path.to_string_lossy(), line_number, text));
+        }
+    }
+
+    fn finish(&mut self) {
+        let theme = self.printer.theme;
+        let terminal_size = *self.printer.terminal_size;
+
+        let idx_size = self
+            .rows
+            .iter()
+            .map(|(idx, ..)| number_of_digits(idx))
+            .max()
+            .unwrap_or(1);
+        let line_size = self
+            .rows
+            .iter()
+            .map(|(_, _, line, _)| number_of_digits(line))
+            .max()
+            .unwrap_or(1);
+        let path_size = self
+            .rows
+            .iter()
+            .map(|(_, path, ..)| path.chars().count() as u32)
+            .max()
+            .unwrap_or(0)
+            .min(std::cmp::max(terminal_size / 3, 10));
+        let text_size = terminal_size.saturating_sub(idx_size + path_size + line_size + 6);
+
+        for (idx, path, line_number, text) in self.rows.drain(..) {
+            let path = truncate_middle(&path, path_size);
+            let path_padding = " ".repeat((path_size - path.chars().count() as u32) as usize);
+            let text = wrap_text(text.trim_start(), std::cmp::max(text_size, 1), 8, false)
+                .next()
+                .unwrap_or_default();
+
+            println!(
+                "{}  {}{}  {}  {}",
+                theme.index.paint(&pad_number(idx, idx_size)),
+                theme.path.paint(&path),
+                path_padding,
+                theme.line_number.paint(&pad_number(line_number, line_size)),
+                text
+            );
+        }
+    }
+}
+
+/// Print the statistics of the search sent by rg in its summary record.
+pub fn stats_view(elapsed_total: &Elapsed, stats: &Stats, theme: &Theme) {
+    let footer = format!(
+        "{} matches ({} lines) in {}/{} files, {} searched, {} printed, {:.3}s",
+        stats.matches,
+        stats.matched_lines,
+        stats.searches_with_match,
+        stats.searches,
+        human_bytes(stats.bytes_searched),
+        human_bytes(stats.bytes_printed),
+        elapsed_total.as_duration().as_secs_f64(),
+    );
+
+    println!("{}", theme.context.paint(&footer));
+}
+
+/// Print the searches of the history, with the number to give to `vg --search` and their number
+/// of matches.
+pub fn history_view(searches: &[(u32, SearchInfo, u32)], theme: &Theme) {
+    let idx_max = number_of_digits(&searches.iter().map(|(idx, _, _)| *idx).max().unwrap_or(0));
+    let count_max = searches
+        .iter()
+        .map(|(_, _, count)| number_of_digits(count))
+        .max()
+        .unwrap_or(1);
+
+    for (idx, info, count) in searches {
+        // The results read from stdin have no arguments
+        let search = match (&info.pattern, info.args.is_empty()) {
+            (Some(pattern), _) => pattern.clone(),
+            (None, false) => info.args.join(" "),
+            (None, true) => String::from("<stdin>"),
+        };
+        println!(
+            "{} {} {} {} {}",
+            theme.index.paint(&pad_number(*idx, idx_max)),
+            theme.context.paint(&local_date(info.timestamp)),
+            theme.line_number.paint(&pad_number(*count, count_max)),
+            theme.submatch.paint(&search),
+            theme.path.paint(info.cwd.as_deref().unwrap_or_default()),
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -163,35 +542,37 @@ mod tests {
 
     #[test]
     fn test_color_submatch() {
+        colored::control::set_override(true);
+        let theme = Theme::default();
         let text = "aaaaabbbbbcccccdddddeeeee".to_string();
         let submatches = vec![(0, 15)];
-        let colored = color_submatch(&text, &submatches);
+        let colored = color_submatch(&text, &submatches, &theme.submatch);
         println!("{colored:?}");
-        assert_eq!(colored.is_some(), true);
+        assert!(colored.is_some());
         assert_eq!(
             "\u{1b}[1;34maaaaabbbbbccccc\u{1b}[0mdddddeeeee",
             colored.unwrap()
         );
 
         let submatches = vec![(0, 5)];
-        let colored = color_submatch(&text, &submatches);
-        assert_eq!(colored.is_some(), true);
+        let colored = color_submatch(&text, &submatches, &theme.submatch);
+        assert!(colored.is_some());
         assert_eq!(
             "\u{1b}[1;34maaaaa\u{1b}[0mbbbbbcccccdddddeeeee",
             colored.unwrap()
         );
 
         let submatches = vec![(10, 25)];
-        let colored = color_submatch(&text, &submatches);
-        assert_eq!(colored.is_some(), true);
+        let colored = color_submatch(&text, &submatches, &theme.submatch);
+        assert!(colored.is_some());
         assert_eq!(
             "aaaaabbbbb\u{1b}[1;34mcccccdddddeeeee\u{1b}[0m",
             colored.unwrap()
         );
 
         let submatches = vec![(0, 24)];
-        let colored = color_submatch(&text, &submatches);
-        assert_eq!(colored.is_some(), true);
+        let colored = color_submatch(&text, &submatches, &theme.submatch);
+        assert!(colored.is_some());
         assert_eq!(
             "\u{1b}[1;34maaaaabbbbbcccccdddddeeee\u{1b}[0me",
             colored.unwrap()
@@ -199,8 +580,28 @@ mod tests {
 
         let submatches = vec![(0, 26)];
         let result = panic::catch_unwind(|| {
-            color_submatch(&text, &submatches);
+            color_submatch(&text, &submatches, &theme.submatch);
         });
-        assert_eq!(true, result.is_err());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_mark_submatches() {
+        let style = Style::default();
+        let (text, submatches) = mark_submatches("a foo b foo", &[(2, 5), (8, 11)], 12, &style);
+        assert_eq!("a [12]foo b [13]foo", text);
+        assert_eq!(vec![(6, 9), (16, 19)], submatches);
+    }
+
+    #[test]
+    fn test_lossy_line() {
+        let (text, submatches) = lossy_line(b"foo bar\n", &[(4, 7)]);
+        assert_eq!("foo bar", text);
+        assert_eq!(vec![(4, 7)], submatches);
+
+        // The invalid byte is replaced by U+FFFD which is 3 bytes long
+        let (text, submatches) = lossy_line(b"caf\xe9 bar\n", &[(0, 4), (5, 8)]);
+        assert_eq!("caf\u{fffd} bar", text);
+        assert_eq!(vec![(0, 6), (7, 10)], submatches);
     }
 }
@@ -1,167 +1,1518 @@
+use crate::config::StoreScope;
 use bincode;
-use log::debug;
+use log::{debug, info};
 use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
 use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::io::{BufRead, BufReader};
-use std::process::Command;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::io::AsRawFd;
 use std::*;
 
-pub type Index = (String, u32);
-pub type IndexOffset = usize;
+/// Location of a match.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Index {
+    /// Path of the matched file, stored as raw bytes since it might not be valid UTF-8
+    pub path: Vec<u8>,
+    pub line: u32,
+    /// 1-based column of the match, in characters
+    pub column: u32,
+    /// 1-based column of the match, in bytes
+    pub byte_column: u32,
+    /// Text of the matched line, without the line break
+    pub text: Vec<u8>,
+    /// Text of the match
+    pub submatch: Vec<u8>,
+    /// Byte ranges of all the matches of the line, to print it again
+    pub ranges: Vec<(u32, u32)>,
+    /// Entry holding the text and the ranges of the line when this one does not. In a
+    /// per-submatch store, only the first entry of a line has them, the next ones point to it.
+    pub line_entry: Option<u32>,
+}
+
+/// Information about the search, written after the header of the match file.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct SearchInfo {
+    /// Pattern given to rg, if it could be found in its arguments
+    pub pattern: Option<String>,
+    /// Directory the search was run from
+    pub cwd: Option<String>,
+    /// Arguments given to rg, empty when the results are read from stdin
+    pub args: Vec<String>,
+    /// Start of the search, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Each submatch of a line has its own entry
+    pub per_submatch: bool,
+}
+
+/// Search kept in the history directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub match_file: String,
+    pub index_file: String,
+}
+
+pub type IndexOffset = u64;
+
+/// Magic bytes at the beginning of the binary match file.
+pub static STORE_MAGIC: [u8; 4] = *b"CGVG";
+/// Magic bytes at the beginning of the offset index file.
+pub static INDEX_MAGIC: [u8; 4] = *b"CGVI";
+/// Version of the binary store, bumped each time the layout of the files changes.
+pub static STORE_VERSION: u32 = 9;
+
+/// Age after which the quickfix files of the previous calls of vg are removed.
+const QUICKFIX_FILE_AGE: time::Duration = time::Duration::from_secs(60);
+
+/// Size in bytes of one offset in the index file.
+const OFFSET_SIZE: usize = mem::size_of::<IndexOffset>();
 
 #[derive(Debug)]
 pub enum CgVgError {
     LoadIndexOob(u32, u32),
     LoadIndexFormat,
+    /// The file does not start with the expected magic bytes.
+    StoreFormat(String),
+    /// The file has been written by an incompatible version of cgvg.
+    StoreVersion(u32),
+    /// The match file and the index file have been written by different searches.
+    StoreGeneration(String),
+    Io(io::Error),
+}
+
+impl Index {
+    /// Location at the beginning of the line, without its text.
+    pub fn new(path: Vec<u8>, line: u32) -> Self {
+        Index {
+            path,
+            line,
+            column: 1,
+            byte_column: 1,
+            text: vec![],
+            submatch: vec![],
+            ranges: vec![],
+            line_entry: None,
+        }
+    }
+
+    /// Location of the match between the bytes `start` and `end` of the line `text`.
+    pub fn with_submatch(path: Vec<u8>, line: u32, text: &[u8], start: usize, end: usize) -> Self {
+        let offset = cmp::min(start, text.len());
+        let submatch = text[offset..cmp::max(offset, cmp::min(end, text.len()))].to_vec();
+        // Invalid UTF-8 sequences count as one character each
+        let chars = String::from_utf8_lossy(&text[..offset]).chars().count();
+        let text = text.strip_suffix(b"\n").unwrap_or(text);
+        let text = text.strip_suffix(b"\r").unwrap_or(text);
+        let ranges = vec![(offset as u32, (offset + submatch.len()) as u32)];
+
+        Index {
+            path,
+            line,
+            column: chars as u32 + 1,
+            byte_column: offset as u32 + 1,
+            text: text.to_vec(),
+            submatch,
+            ranges,
+            line_entry: None,
+        }
+    }
+}
+
+impl From<io::Error> for CgVgError {
+    fn from(err: io::Error) -> Self {
+        CgVgError::Io(err)
+    }
+}
+
+impl fmt::Display for CgVgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CgVgError::LoadIndexOob(idx, len) => write!(
+                f,
+                "Provided Index ({}) greater that number of possibilities ({}).",
+                idx, len
+            ),
+            CgVgError::LoadIndexFormat => {
+                write!(
+                    f,
+                    "Could not load select index. It might be a formating issue."
+                )
+            }
+            CgVgError::StoreFormat(path) => write!(
+                f,
+                "State file {path} is corrupted or has an unknown format. Run cg again."
+            ),
+            CgVgError::StoreVersion(version) => write!(
+                f,
+                "State files were written by another version of cgvg (v{version}). Run cg again."
+            ),
+            CgVgError::StoreGeneration(path) => write!(
+                f,
+                "State file {path} does not belong to the match file, they were written by \
+                 different searches. Run cg again."
+            ),
+            CgVgError::Io(err) => write!(f, "Could not access state files: {err}"),
+        }
+    }
+}
+
+/// Header written at the beginning of both the match file and the index file.
+///
+/// Both files share the same header layout, only the magic differs. It lets `vg` tell a binary
+/// store from the legacy text format. The generation is shared by the two files of a store, so
+/// a match file is never read with the index of another search.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct StoreHeader {
+    pub magic: [u8; 4],
+    pub version: u32,
+    pub generation: u64,
+}
+
+impl StoreHeader {
+    pub fn new(magic: [u8; 4], generation: u64) -> Self {
+        StoreHeader {
+            magic,
+            version: STORE_VERSION,
+            generation,
+        }
+    }
+
+    /// Size in bytes of the serialized header.
+    pub fn size() -> usize {
+        bincode::serialized_size(&StoreHeader::new(STORE_MAGIC, 0)).expect("header size") as usize
+    }
+
+    /// Generation of a new store, from the time and the process id.
+    fn new_generation() -> u64 {
+        let nanos = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        nanos ^ ((process::id() as u64) << 32)
+    }
+
+    /// Check that the headers of the match file and of the index file are from the same store.
+    fn check_generation(
+        data: &StoreHeader,
+        index: &StoreHeader,
+        index_path: &str,
+    ) -> Result<(), CgVgError> {
+        match data.generation == index.generation {
+            true => Ok(()),
+            false => Err(CgVgError::StoreGeneration(index_path.to_string())),
+        }
+    }
+
+    /// Read and check a header from the beginning of `bytes`.
+    fn check(bytes: &[u8], magic: [u8; 4], file_path: &str) -> Result<StoreHeader, CgVgError> {
+        if bytes.len() < StoreHeader::size() {
+            return Err(CgVgError::StoreFormat(file_path.to_string()));
+        }
+
+        let header: StoreHeader = bincode::deserialize(&bytes[..StoreHeader::size()])
+            .map_err(|_| CgVgError::StoreFormat(file_path.to_string()))?;
+
+        if header.magic != magic {
+            return Err(CgVgError::StoreFormat(file_path.to_string()));
+        }
+
+        if header.version != STORE_VERSION {
+            return Err(CgVgError::StoreVersion(header.version));
+        }
+
+        Ok(header)
+    }
+}
+
+/// Advisory lock of a store, taken on the file `<match file>.lock` with `flock`. The files of
+/// the store are replaced or appended to with an exclusive lock and read with a shared one, so a
+/// reader never sees the match file of a search with the index of another. The lock is released
+/// when dropped.
+struct StoreLock {
+    file: File,
+}
+
+impl StoreLock {
+    /// Open the lock file of the store, without locking it.
+    fn open(file_path: &str) -> Result<File, CgVgError> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(format!("{file_path}.lock"))?)
+    }
+
+    /// Lock `file`, the lock file of the store or a duplicate of its descriptor.
+    fn lock(file: File, operation: libc::c_int) -> Result<StoreLock, CgVgError> {
+        while unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(CgVgError::Io(err));
+            }
+        }
+
+        Ok(StoreLock { file })
+    }
+
+    /// Lock to read the store. The store can still be read without the lock when the lock file
+    /// cannot be created, e.g. in a read-only directory, the generations are checked anyway.
+    fn shared(file_path: &str) -> Option<StoreLock> {
+        StoreLock::open(file_path)
+            .and_then(|file| StoreLock::lock(file, libc::LOCK_SH))
+            .inspect_err(|e| debug!("cannot lock {file_path}: {e:?}"))
+            .ok()
+    }
 }
 
-pub fn save_text<'a>(tuples: Vec<Index>, file_path: &'a str) {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(file_path)
-        .expect(&format!("cannot open file {}", &file_path));
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        // A duplicated descriptor shares the lock with the original one, which stays open
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Temporary file written next to `file_path` before it is renamed to it.
+fn temp_path(file_path: &str) -> String {
+    format!("{file_path}.{}.tmp", process::id())
+}
+
+/// Remove the temporary files left next to `file_path` by the processes that are not running
+/// anymore, e.g. a cg killed before its first flush.
+fn remove_stale_temp_files(file_path: &str) {
+    let file_path = path::Path::new(file_path);
+    let (Some(dir), Some(name)) = (file_path.parent(), file_path.file_name()) else {
+        return;
+    };
+    let dir = match dir.as_os_str().is_empty() {
+        true => path::Path::new("."),
+        false => dir,
+    };
+    let Ok(read_dir) = fs::read_dir(dir) else {
+        return;
+    };
+
+    let prefix = format!("{}.", name.to_string_lossy());
+    for file in read_dir.flatten() {
+        let file_name = file.file_name();
+        let pid = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(&prefix))
+            .and_then(|rest| rest.strip_suffix(".tmp"))
+            .and_then(|pid| pid.parse::<libc::pid_t>().ok());
+
+        let Some(pid) = pid.filter(|pid| *pid > 0) else {
+            continue;
+        };
+        let running = unsafe { libc::kill(pid, 0) } == 0
+            || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH);
+        if !running {
+            debug!("removing stale temporary file {}", file.path().display());
+            fs::remove_file(file.path()).ok();
+        }
+    }
+}
+
+/// Return true if the file at `file_path` starts with the binary store magic.
+pub fn is_binary_store(file_path: &str) -> Result<bool, CgVgError> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(file_path)?;
+
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == STORE_MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(CgVgError::Io(e)),
+    }
+}
+
+#[cfg(test)]
+pub fn save_text(tuples: Vec<Index>, file_path: &str) {
+    let tmp_path = temp_path(file_path);
+    let mut file =
+        File::create(&tmp_path).unwrap_or_else(|_| panic!("cannot open file {}", tmp_path));
 
     // Write the actual data
     for data in tuples.iter() {
-        file.write_all(&format!("{} {}\n", data.1, data.0).as_bytes())
+        file.write_all(format!("{} ", data.line).as_bytes())
+            .and_then(|_| file.write_all(&data.path))
+            .and_then(|_| file.write_all(b"\n"))
             .expect("Cannot write data");
     }
 
     file.sync_all().expect("cannot sync");
+    let _lock = StoreLock::open(file_path)
+        .and_then(|file| StoreLock::lock(file, libc::LOCK_EX))
+        .expect("cannot lock the match file");
+    fs::rename(&tmp_path, file_path).expect("cannot replace the match file");
 }
 
-pub fn load_text<'a>(idx: u32, file_path: &'a str) -> Result<Index, CgVgError> {
-    let lines: Vec<_> = BufReader::new(File::open(file_path).expect("Cannot open file"))
-        .lines()
-        .filter_map(|line| line.ok())
-        // How to find the number of lines without this collect ?
-        .collect();
+/// Create a new file named from the clock and the process id in `dir`, only readable and
+/// writable by the user.
+///
+/// The file is created with `O_EXCL`, so a file or a symlink planted in a shared directory like
+/// `/tmp` is never written through, another name is tried instead.
+fn create_private_file(dir: &path::Path, prefix: &str, ext: &str) -> io::Result<(File, String)> {
+    use std::os::unix::fs::OpenOptionsExt;
 
-    match lines
+    let mut attempts = 0;
+    loop {
+        let name = StoreHeader::new_generation();
+        let file_path = dir.join(format!("{prefix}-{name:016x}.{ext}"));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&file_path);
+        match file {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < 16 => attempts += 1,
+            file => return Ok((file?, file_path.to_string_lossy().to_string())),
+        }
+    }
+}
+
+/// Remove the files of `dir` created with [`create_private_file`] and the same prefix and
+/// extension that are older than `age`.
+fn remove_old_private_files(dir: &path::Path, prefix: &str, ext: &str, age: time::Duration) {
+    let Ok(read_dir) = fs::read_dir(dir) else {
+        return;
+    };
+
+    let (prefix, ext) = (format!("{prefix}-"), format!(".{ext}"));
+    for file in read_dir.flatten() {
+        let file_name = file.file_name();
+        if !file_name
+            .to_str()
+            .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(&ext))
+        {
+            continue;
+        }
+
+        // The files of the other users of a shared directory cannot be removed, they are skipped
+        let old = fs::symlink_metadata(file.path())
+            .and_then(|meta| meta.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|elapsed| elapsed > age));
+        if old {
+            debug!("removing old file {}", file.path().display());
+            fs::remove_file(file.path()).ok();
+        }
+    }
+}
+
+/// Save the entries in the `path:line:column:text` format read by the quickfix of vim
+/// (`vim -q`) and the grep-mode of emacs, in a new private file of `dir`. Return the path of the
+/// file. Like `rg --vimgrep`, the column is in bytes and only the first line of multiline matches
+/// is written.
+///
+/// The files written by the previous calls are removed, once they are old enough to have been
+/// read by their editor.
+pub fn save_quickfix(tuples: &[Index], dir: &path::Path) -> Result<String, CgVgError> {
+    remove_old_private_files(dir, "vg-quickfix", "txt", QUICKFIX_FILE_AGE);
+    let (file, file_path) = create_private_file(dir, "vg-quickfix", "txt")?;
+    let mut file = io::BufWriter::new(file);
+
+    for data in tuples.iter() {
+        let text = data.text.split(|c| *c == b'\n').next().unwrap_or_default();
+        file.write_all(&data.path)?;
+        file.write_all(format!(":{}:{}:", data.line, data.byte_column).as_bytes())?;
+        file.write_all(text)?;
+        file.write_all(b"\n")?;
+    }
+
+    file.flush()?;
+    Ok(file_path)
+}
+
+/// Parse one line of the text format: `<line number> <path>`. The text format has no column.
+fn parse_text_line(line: &[u8]) -> Result<Index, CgVgError> {
+    let space = line
         .iter()
-        .map(|line| line.to_string())
-        .skip((idx) as usize)
-        .next()
-    {
-        Some(line) => {
-            let (idx_str, path) = line.split_once(' ').unwrap();
-            let idx = match idx_str.parse::<u32>() {
-                Ok(idx) => idx,
-                Err(_) => return Err(CgVgError::LoadIndexFormat),
+        .position(|c| *c == b' ')
+        .ok_or(CgVgError::LoadIndexFormat)?;
+    let idx = str::from_utf8(&line[..space])
+        .ok()
+        .and_then(|idx_str| idx_str.parse::<u32>().ok())
+        .ok_or(CgVgError::LoadIndexFormat)?;
+
+    Ok(Index::new(line[space + 1..].to_vec(), idx))
+}
+
+#[cfg(test)]
+pub fn load_text(idx: u32, file_path: &str) -> Result<Index, CgVgError> {
+    let mut nb_lines = 0;
+
+    for line in BufReader::new(File::open(file_path)?).split(b'\n') {
+        let line = line?;
+        if nb_lines == idx {
+            return parse_text_line(&line);
+        }
+        nb_lines += 1;
+    }
+
+    Err(CgVgError::LoadIndexOob(idx, nb_lines))
+}
+
+/// Load every entry of a match file saved with the text format.
+pub fn load_text_all(file_path: &str) -> Result<Vec<Index>, CgVgError> {
+    BufReader::new(File::open(file_path)?)
+        .split(b'\n')
+        .map(|line| parse_text_line(&line?))
+        .collect()
+}
+
+/// Read the search information following the header of the match file, return it with the
+/// offset of the first entry.
+fn read_info(bytes: &[u8], file_path: &str) -> Result<(SearchInfo, usize), CgVgError> {
+    let header_size = StoreHeader::size();
+    let info: SearchInfo = bincode::deserialize(&bytes[header_size..])
+        .map_err(|_| CgVgError::StoreFormat(file_path.to_string()))?;
+    let size = bincode::serialized_size(&info).expect("info size") as usize;
+
+    Ok((info, header_size + size))
+}
+
+/// Generation of the binary store, shared by its two files.
+fn load_generation(file_path: &str) -> Result<u64, CgVgError> {
+    let mut header = vec![0u8; StoreHeader::size()];
+    File::open(file_path)?
+        .read_exact(&mut header)
+        .map_err(|_| CgVgError::StoreFormat(file_path.to_string()))?;
+
+    Ok(StoreHeader::check(&header, STORE_MAGIC, file_path)?.generation)
+}
+
+/// Load the information about the search of the binary store.
+pub fn load_info(file_path: &str) -> Result<SearchInfo, CgVgError> {
+    let _lock = StoreLock::shared(file_path);
+    let file = File::open(file_path)?;
+    let mmap = unsafe { Mmap::map(&file)? };
+    StoreHeader::check(&mmap, STORE_MAGIC, file_path)?;
+
+    Ok(read_info(&mmap, file_path)?.0)
+}
+
+/// Binary store mapped in memory.
+///
+/// Both files are mapped once, with the shared lock of the store, so all the entries read from
+/// it belong to the same search. The store is replaced by renaming new files over it, but the
+/// cg writing it appends entries and offsets to the same files until it finishes: only the
+/// entries of the offsets in the mapped index are read, the ones appended afterwards are not
+/// seen.
+pub struct Store {
+    data: Mmap,
+    index: Mmap,
+    file_path: String,
+    /// Offset of the first entry, right after the search information
+    first_entry: usize,
+    info: SearchInfo,
+}
+
+impl Store {
+    /// Map the store, migrating it first if it has the legacy text format.
+    pub fn open(file_path: &str, index_path: &str) -> Result<Store, CgVgError> {
+        migrate(file_path, index_path)?;
+        Store::map(file_path, index_path)
+    }
+
+    /// Map a binary store.
+    pub fn map(file_path: &str, index_path: &str) -> Result<Store, CgVgError> {
+        let _lock = StoreLock::shared(file_path);
+        let index_file = File::open(index_path)?;
+        let index = unsafe { Mmap::map(&index_file)? };
+        let index_header = StoreHeader::check(&index, INDEX_MAGIC, index_path)?;
+
+        if (index.len() - StoreHeader::size()) % OFFSET_SIZE != 0 {
+            return Err(CgVgError::StoreFormat(index_path.to_string()));
+        }
+
+        let file = File::open(file_path)?;
+        let data = unsafe { Mmap::map(&file)? };
+        let header = StoreHeader::check(&data, STORE_MAGIC, file_path)?;
+        StoreHeader::check_generation(&header, &index_header, index_path)?;
+        let (info, first_entry) = read_info(&data, file_path)?;
+
+        Ok(Store {
+            data,
+            index,
+            file_path: file_path.to_string(),
+            first_entry,
+            info,
+        })
+    }
+
+    /// Number of entries in the store.
+    pub fn len(&self) -> u32 {
+        ((self.index.len() - StoreHeader::size()) / OFFSET_SIZE) as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Information about the search of the store.
+    pub fn info(&self) -> &SearchInfo {
+        &self.info
+    }
+
+    /// End offset of the entry `idx` in the match file.
+    fn end_offset(&self, idx: usize) -> usize {
+        let start = StoreHeader::size() + idx * OFFSET_SIZE;
+        let bytes = &self.index[start..start + OFFSET_SIZE];
+        IndexOffset::from_le_bytes(bytes.try_into().expect("offset size")) as usize
+    }
+
+    /// Load the entry `idx`, with the text and the ranges of its line.
+    ///
+    /// The index file contains the end offset of each entry in the match file, so the lookup
+    /// only reads two offsets and the entry itself, and the first entry of the line if it holds
+    /// the text.
+    pub fn get(&self, idx: u32) -> Result<Index, CgVgError> {
+        let mut index = self.entry(idx)?;
+        if let Some(line_entry) = index.line_entry.filter(|line_entry| *line_entry < idx) {
+            let line = self.entry(line_entry)?;
+            index.text = line.text;
+            index.ranges = line.ranges;
+        }
+        Ok(index)
+    }
+
+    /// Load the entry `idx` as it is stored.
+    fn entry(&self, idx: u32) -> Result<Index, CgVgError> {
+        if idx >= self.len() {
+            return Err(CgVgError::LoadIndexOob(idx, self.len()));
+        }
+
+        let start = match idx {
+            0 => self.first_entry,
+            idx => self.end_offset(idx as usize - 1),
+        };
+        let end = self.end_offset(idx as usize);
+
+        if start > end || end > self.data.len() {
+            return Err(CgVgError::StoreFormat(self.file_path.clone()));
+        }
+
+        debug!("get idx: {idx:?} at offsets {start:?} {end:?}");
+        bincode::deserialize(&self.data[start..end]).map_err(|_| CgVgError::LoadIndexFormat)
+    }
+
+    /// Load all the entries, in order. Unlike [`Store::get`], the entries pointing to the first
+    /// entry of their line are kept without its text.
+    pub fn entries(&self) -> Result<Vec<Index>, CgVgError> {
+        (0..self.len()).map(|idx| self.entry(idx)).collect()
+    }
+}
+
+/// Load the entry `idx` of the binary store, see [`Store::get`].
+#[cfg(test)]
+pub fn load_binary(idx: u32, file_path: &str, index_path: &str) -> Result<Index, CgVgError> {
+    Store::map(file_path, index_path)?.get(idx)
+}
+
+/// Load all the entries of the binary store, in order.
+#[cfg(test)]
+pub fn load_binary_all(file_path: &str, index_path: &str) -> Result<Vec<Index>, CgVgError> {
+    Store::map(file_path, index_path)?.entries()
+}
+
+/// Writer of the binary store, entries are appended one by one.
+///
+/// The match file contains a header and the [`SearchInfo`] followed by the bincode serialized
+/// entries. The index file
+/// contains a header followed by the little-endian end offset of each entry in the match file.
+///
+/// Offsets are only written to the index once the data they point to has been flushed, so the
+/// store stays readable if cg is interrupted: it contains the entries of the last flush.
+///
+/// Both files are written to temporary files, which replace the previous store at the first
+/// flush, with the exclusive lock of the store. The next offsets are appended with the lock too,
+/// the lock file is opened once when the writer is created.
+/// The temporary files are removed if the writer is dropped before the first flush, and the ones
+/// left by killed processes are removed when a new store is created.
+pub struct StoreWriter {
+    data: io::BufWriter<File>,
+    index: File,
+    /// Lock file of the store, locked at each flush
+    lock_file: File,
+    file_path: String,
+    index_path: String,
+    /// The temporary files have replaced the previous store
+    published: bool,
+    /// Offsets of the entries pushed since the last flush.
+    pending: Vec<u8>,
+    offset: IndexOffset,
+    nb_entries: u32,
+}
+
+impl StoreWriter {
+    pub fn create(
+        file_path: &str,
+        index_path: &str,
+        info: &SearchInfo,
+    ) -> Result<StoreWriter, CgVgError> {
+        remove_stale_temp_files(file_path);
+        remove_stale_temp_files(index_path);
+
+        let lock_file = StoreLock::open(file_path)?;
+        let data = io::BufWriter::new(File::create(temp_path(file_path))?);
+        let index = match File::create(temp_path(index_path)) {
+            Ok(index) => index,
+            Err(e) => {
+                fs::remove_file(temp_path(file_path)).ok();
+                return Err(CgVgError::Io(e));
+            }
+        };
+        let mut writer = StoreWriter {
+            data,
+            index,
+            lock_file,
+            file_path: file_path.to_string(),
+            index_path: index_path.to_string(),
+            published: false,
+            pending: vec![],
+            offset: 0,
+            nb_entries: 0,
+        };
+
+        let generation = StoreHeader::new_generation();
+        let mut header = bincode::serialize(&StoreHeader::new(STORE_MAGIC, generation))
+            .expect("cannot serialize");
+        bincode::serialize_into(&mut header, info).expect("cannot serialize");
+        writer.data.write_all(&header)?;
+        bincode::serialize_into(
+            &mut writer.index,
+            &StoreHeader::new(INDEX_MAGIC, generation),
+        )
+        .expect("cannot serialize");
+        writer.offset = header.len() as IndexOffset;
+
+        Ok(writer)
+    }
+
+    /// Append an entry to the store, it is readable after the next flush.
+    pub fn push(&mut self, tuple: &Index) -> Result<(), CgVgError> {
+        let entry = bincode::serialize(tuple).expect("cannot serialize");
+        self.data.write_all(&entry)?;
+
+        self.offset += entry.len() as IndexOffset;
+        self.pending.extend_from_slice(&self.offset.to_le_bytes());
+        self.nb_entries += 1;
+
+        Ok(())
+    }
+
+    /// Write the pending entries, then their offsets in the index. The first flush replaces the
+    /// previous store.
+    pub fn flush(&mut self) -> Result<(), CgVgError> {
+        self.data.flush()?;
+
+        let lock = self.lock()?;
+        self.write_pending(&lock)
+    }
+
+    /// Take the exclusive lock of the store, to replace or append to its files, on the lock file
+    /// opened by [`StoreWriter::create`].
+    fn lock(&self) -> Result<StoreLock, CgVgError> {
+        StoreLock::lock(self.lock_file.try_clone()?, libc::LOCK_EX)
+    }
+
+    /// Write the offsets of the flushed entries in the index, and replace the previous store if
+    /// it has not been done yet. The exclusive `lock` of the store is held by the caller.
+    fn write_pending(&mut self, _lock: &StoreLock) -> Result<(), CgVgError> {
+        self.index.write_all(&self.pending)?;
+        if !self.published {
+            fs::rename(temp_path(&self.file_path), &self.file_path)?;
+            fs::rename(temp_path(&self.index_path), &self.index_path)?;
+            self.published = true;
+        }
+        self.pending.clear();
+
+        Ok(())
+    }
+
+    /// Flush and sync both files, return the number of entries in the store.
+    pub fn finish(mut self) -> Result<u32, CgVgError> {
+        self.flush()?;
+        self.data.get_ref().sync_all()?;
+        self.index.sync_all()?;
+
+        debug!("saved {} entries", self.nb_entries);
+        Ok(self.nb_entries)
+    }
+}
+
+impl Drop for StoreWriter {
+    fn drop(&mut self) {
+        if !self.published {
+            fs::remove_file(temp_path(&self.file_path)).ok();
+            fs::remove_file(temp_path(&self.index_path)).ok();
+        }
+    }
+}
+
+/// Save the entries in the binary store, see [`StoreWriter`] for the layout of the files.
+pub fn save_binary(tuples: Vec<Index>, file_path: &str, index_path: &str) -> Result<(), CgVgError> {
+    let mut writer = StoreWriter::create(file_path, index_path, &SearchInfo::default())?;
+
+    for tuple in &tuples {
+        writer.push(tuple)?;
+    }
+
+    writer.finish()?;
+    Ok(())
+}
+
+/// Migrate a match file written with the legacy text format to the binary store, so the lookups
+/// are done by offset.
+///
+/// The binary store is written next to the text one, then replaced with the exclusive lock of
+/// the store. A store published by cg in the meantime is kept.
+fn migrate(file_path: &str, index_path: &str) -> Result<(), CgVgError> {
+    if is_binary_store(file_path)? {
+        return Ok(());
+    }
+
+    info!("Migrating text match file {file_path} to the binary format");
+    let mut writer = StoreWriter::create(file_path, index_path, &SearchInfo::default())?;
+    for tuple in load_text_all(file_path)? {
+        writer.push(&tuple)?;
+    }
+    writer.data.flush()?;
+    writer.data.get_ref().sync_all()?;
+
+    let lock = writer.lock()?;
+    if is_binary_store(file_path)? {
+        debug!("{file_path} has been replaced during the migration");
+        return Ok(());
+    }
+    writer.write_pending(&lock)?;
+    writer.index.sync_all()?;
+
+    Ok(())
+}
+
+/// Load the entry `idx` from the match file, whatever its format.
+#[cfg(test)]
+pub fn load(idx: u32, file_path: &str, index_path: &str) -> Result<Index, CgVgError> {
+    Store::open(file_path, index_path)?.get(idx)
+}
+
+/// Load all the entries of the match file, whatever its format.
+#[cfg(test)]
+pub fn load_all(file_path: &str, index_path: &str) -> Result<Vec<Index>, CgVgError> {
+    Store::open(file_path, index_path)?.entries()
+}
+
+/// Number of entries in the store, whatever its format.
+#[cfg(test)]
+pub fn store_len(file_path: &str, index_path: &str) -> Result<u32, CgVgError> {
+    Ok(Store::open(file_path, index_path)?.len())
+}
+
+/// Home directory of `user`, read from the password database.
+fn user_home(user: &str) -> Option<String> {
+    let name = ffi::CString::new(user).ok()?;
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut result = ptr::null_mut();
+
+    let ret = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() || passwd.pw_dir.is_null() {
+        return None;
+    }
+
+    let dir = unsafe { ffi::CStr::from_ptr(passwd.pw_dir) };
+    Some(dir.to_string_lossy().to_string())
+}
+
+/// Expand `~`, `~user`, `$VAR` and `${VAR}` in a path, like a shell would.
+///
+/// Nothing else is interpreted, so spaces and other special characters are kept as is. An error
+/// is returned for unknown users, unset variables and unterminated `${`.
+pub fn expand_path(path: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+
+    // The tilde is only expanded at the beginning of the path
+    let rest = match path.strip_prefix('~') {
+        Some(rest) => {
+            let end = rest.find('/').unwrap_or(rest.len());
+            let (user, rest) = rest.split_at(end);
+            let home = match user {
+                "" => env::var("HOME")
+                    .map_err(|_| format!("cannot expand ~ in {path:?}: $HOME is not set"))?,
+                user => user_home(user)
+                    .ok_or_else(|| format!("cannot expand {path:?}: unknown user {user:?}"))?,
             };
+            expanded.push_str(&home);
+            rest
+        }
+        None => path,
+    };
 
-            return Ok((path.to_string(), idx));
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut chars = rest.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
         }
-        None => {
-            return Err(CgVgError::LoadIndexOob(idx, lines.len() as u32));
+
+        let name = match chars.peek() {
+            Some((_, '{')) => {
+                let end = rest[i..]
+                    .find('}')
+                    .ok_or_else(|| format!("cannot expand {path:?}: missing }}"))?;
+                let name = &rest[i + 2..i + end];
+                while chars.next_if(|(j, _)| *j <= i + end).is_some() {}
+                name
+            }
+            Some((_, c)) if is_name(*c) => {
+                let start = i + 1;
+                let mut end = start;
+                while let Some((j, c)) = chars.next_if(|(_, c)| is_name(*c)) {
+                    end = j + c.len_utf8();
+                }
+                &rest[start..end]
+            }
+            // A lone `$` is kept
+            _ => {
+                expanded.push(c);
+                continue;
+            }
+        };
+
+        if name.is_empty() || !name.chars().all(is_name) {
+            return Err(format!(
+                "cannot expand {path:?}: invalid variable name {name:?}"
+            ));
+        }
+        let value =
+            env::var(name).map_err(|_| format!("cannot expand {path:?}: ${name} is not set"))?;
+        expanded.push_str(&value);
+    }
+
+    Ok(expanded)
+}
+
+/// Name of the terminal of the process: the tmux pane, or else the TTY of stdin, stdout or
+/// stderr.
+fn terminal_name() -> Option<String> {
+    if let Some(pane) = env::var("TMUX_PANE").ok().filter(|pane| !pane.is_empty()) {
+        return Some(format!("tmux:{pane}"));
+    }
+
+    // cg can read from a pipe and vg is often started from the same terminal
+    let mut buffer = vec![0 as libc::c_char; 256];
+    for fd in [0, 1, 2] {
+        let ret = unsafe { libc::ttyname_r(fd, buffer.as_mut_ptr(), buffer.len()) };
+        if ret == 0 {
+            let name = unsafe { ffi::CStr::from_ptr(buffer.as_ptr()) };
+            return Some(name.to_string_lossy().to_string());
         }
     }
+
+    None
 }
 
-pub fn load_binary<'a>(
-    idx: u32,
-    file_path: &'a str,
-    index_path: &'a str,
-) -> Result<Index, CgVgError> {
-    // Open the binary file
-    let index_file = File::open(index_path).expect("cannot open file");
-    let reader = std::io::BufReader::new(index_file);
+/// Closest directory containing `dir` with a `.git` directory or file, `dir` itself if there
+/// is none.
+fn project_root(dir: &path::Path) -> &path::Path {
+    dir.ancestors()
+        .find(|ancestor| ancestor.join(".git").exists())
+        .unwrap_or(dir)
+}
 
-    // Deserialize the array of u16 from the file
-    let indexes: Vec<IndexOffset> = bincode::deserialize_from(reader).expect("error");
+/// Key of the store for the `scope`, `None` when the store is shared.
+///
+/// The directory scopes are resolved from `cwd`. When the terminal cannot be found the store is
+/// shared.
+pub fn scope_key(scope: &StoreScope, cwd: &path::Path) -> Option<String> {
+    match scope {
+        StoreScope::Global => None,
+        StoreScope::Project => Some(project_root(cwd).to_string_lossy().to_string()),
+        StoreScope::Directory => Some(cwd.to_string_lossy().to_string()),
+        StoreScope::Terminal => terminal_name(),
+    }
+}
 
-    if idx as usize >= indexes.len() {
-        return Err(CgVgError::LoadIndexOob(idx, indexes.len() as u32));
+/// Directory of the stores of the scopes: `$XDG_STATE_HOME/cgvg`, `~/.local/state/cgvg` by
+/// default.
+pub fn scopes_dir() -> String {
+    match env::var("XDG_STATE_HOME") {
+        Ok(dir) if path::Path::new(&dir).is_absolute() => format!("{dir}/cgvg"),
+        _ => String::from("~/.local/state/cgvg"),
     }
+}
 
-    let start = if idx == 0 {
-        0
-    } else {
-        indexes[idx as usize - 1]
-    } as usize;
+/// Path of the store file of a scope: the files of a scope are in the directory of `dir` named
+/// after the 64-bit FNV-1a hash of the `key`, `~/.cgvg.match` becomes `<dir>/<hash>/cgvg.match`.
+pub fn scoped_path(dir: &str, file_path: &str, key: &str) -> String {
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
 
-    let end = indexes[idx as usize] as usize;
+    let name = path::Path::new(file_path)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let name = name.strip_prefix('.').unwrap_or(&name);
+    path::Path::new(dir)
+        .join(format!("{hash:016x}"))
+        .join(name)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Extension of the match files in the history directory.
+const HISTORY_MATCH_EXT: &str = "match";
+/// Extension of the index files in the history directory.
+const HISTORY_INDEX_EXT: &str = "idx";
 
-    // Memory-map the file
-    let file = OpenOptions::new().read(true).open(file_path).unwrap();
-    let mmap = unsafe { Mmap::map(&file).expect("map failed") };
+/// Searches kept in the history directory `dir`, the newest first.
+pub fn history_entries(dir: &str) -> Result<Vec<HistoryEntry>, CgVgError> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(CgVgError::Io(e)),
+    };
 
-    // Retrieve and deserialize tuples from the memory-mapped file
-    debug!("get idx: {idx:?} at offsets {start:?} {end:?}");
-    let data = &mmap[start..end];
-    let tuple: Index = bincode::deserialize(data).expect("cannot derialize");
+    let mut entries = vec![];
+    for file in read_dir {
+        let match_file = file?.path();
+        if match_file.extension() != Some(ffi::OsStr::new(HISTORY_MATCH_EXT)) {
+            continue;
+        }
+        let index_file = match_file.with_extension(HISTORY_INDEX_EXT);
+        if index_file.exists() {
+            entries.push(HistoryEntry {
+                match_file: match_file.to_string_lossy().to_string(),
+                index_file: index_file.to_string_lossy().to_string(),
+            });
+        }
+    }
 
-    Ok(tuple)
+    // The names start with the zero padded timestamp of the search, then its generation
+    entries.sort_by(|a, b| b.match_file.cmp(&a.match_file));
+    Ok(entries)
 }
 
-// First function that saves the entire Vec
-pub fn save_binary<'a>(tuples: Vec<Index>, file_path: &'a str, index_path: &'a str) {
-    // Serialize tuples to a binary format
-    let serialized_data: Vec<Vec<u8>> = tuples
+/// Searches of the history before the one of the store `file_path`, the newest first. The store
+/// is in the history too when the last cg has not replaced it, it is skipped.
+pub fn previous_searches(dir: &str, file_path: &str) -> Result<Vec<HistoryEntry>, CgVgError> {
+    let current = load_generation(file_path).ok();
+    let mut entries = history_entries(dir)?;
+    entries.retain(|entry| load_generation(&entry.match_file).ok() != current);
+
+    Ok(entries)
+}
+
+/// Link a file, copying it when it is on another file system.
+fn link_file(from: &str, to: &path::Path) -> io::Result<()> {
+    fs::hard_link(from, to).or_else(|_| fs::copy(from, to).map(|_| ()))
+}
+
+/// Keep the search of the store in the history directory `dir` before it is replaced, and
+/// remove the searches older than the `size` newest ones.
+///
+/// The store stays in place until the next one replaces it. Its files are linked to the history,
+/// they are only appended to by the cg that wrote them, which has finished by the time the next
+/// search is archived. Stores without search information, like the legacy text format, are not
+/// kept.
+pub fn archive_search(
+    dir: &str,
+    file_path: &str,
+    index_path: &str,
+    size: usize,
+) -> Result<(), CgVgError> {
+    let info = match fs::metadata(index_path) {
+        Ok(_) => load_info(file_path).and_then(|info| Ok((info, load_generation(file_path)?))),
+        Err(e) => Err(CgVgError::Io(e)),
+    };
+
+    if let Some((info, generation)) = info.ok().filter(|_| size > 0) {
+        fs::create_dir_all(dir)?;
+        // The generation tells apart the searches started in the same millisecond
+        let name = format!("{:020}-{generation:016x}", info.timestamp);
+        let stem = path::Path::new(dir).join(name);
+
+        // The store is already in the history when the last cg did not replace it
+        if !stem.with_extension(HISTORY_MATCH_EXT).exists() {
+            debug!("archiving {file_path} to {}", stem.display());
+            let _lock = StoreLock::shared(file_path);
+            link_file(file_path, &stem.with_extension(HISTORY_MATCH_EXT))?;
+            link_file(index_path, &stem.with_extension(HISTORY_INDEX_EXT))?;
+        }
+    }
+
+    for entry in history_entries(dir)?.iter().skip(size) {
+        debug!("pruning {}", entry.match_file);
+        fs::remove_file(&entry.match_file)?;
+        fs::remove_file(&entry.index_file)?;
+        // The lock file only exists if the search has been read
+        fs::remove_file(format!("{}.lock", entry.match_file)).ok();
+    }
+
+    Ok(())
+}
+
+/// Whether `path` is a file that can be executed.
+fn is_executable(path: &path::Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+/// Look for `program` in the directories of `search_path`, formatted like `$PATH`.
+fn find_executable_in(program: &str, search_path: &ffi::OsStr) -> Result<String, String> {
+    // A path to the program is not looked up, like in a shell
+    if program.contains('/') {
+        return match is_executable(path::Path::new(program)) {
+            true => Ok(program.to_string()),
+            false => Err(format!("{program} is not an executable file")),
+        };
+    }
+
+    let dirs: Vec<path::PathBuf> = env::split_paths(search_path).collect();
+    for dir in &dirs {
+        // An empty directory is the current one
+        let dir = match dir.as_os_str().is_empty() {
+            true => path::Path::new("."),
+            false => dir.as_path(),
+        };
+        let candidate = dir.join(program);
+        if is_executable(&candidate) {
+            return Ok(candidate.to_string_lossy().to_string());
+        }
+    }
+
+    let searched: Vec<String> = dirs
         .iter()
-        .map(|tuple| bincode::serialize(tuple).unwrap())
+        .map(|dir| dir.to_string_lossy().to_string())
         .collect();
+    Err(format!(
+        "{program} not found in $PATH, searched: {}",
+        searched.join(", ")
+    ))
+}
 
-    // Determine the size of the file needed
-    let _total_size: usize = serialized_data.iter().map(|data| data.len()).sum();
+/// Resolve `program` to the path of an executable file, looking for it in the directories of
+/// `$PATH` when it is not a path already. The error lists the directories searched.
+pub fn find_executable(program: &str) -> Result<String, String> {
+    let search_path = env::var_os("PATH").unwrap_or_default();
+    find_executable_in(program, &search_path)
+}
 
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(file_path)
-        .expect(&format!("cannot open file {}", &file_path));
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut index = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(index_path)
-        .expect("cannot open file");
+    fn tmp_paths(name: &str) -> (String, String) {
+        let dir = env::temp_dir();
+        let pid = process::id();
+        (
+            dir.join(format!("cgvg-{name}-{pid}.match"))
+                .to_string_lossy()
+                .to_string(),
+            dir.join(format!("cgvg-{name}-{pid}.idx"))
+                .to_string_lossy()
+                .to_string(),
+        )
+    }
+
+    #[test]
+    fn test_scope_key() {
+        let dir = env::temp_dir().join(format!("cgvg-scope-{}", process::id()));
+        fs::create_dir_all(dir.join("src").join("cg")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        let cwd = dir.join("src").join("cg");
 
-    // Since max size of linux file is 255 chars, u16 should be enough for all cases to store
-    // an u32 and the file path
-    let mut offsets: Vec<IndexOffset> = vec![];
-    let mut total_size: IndexOffset = 0;
+        assert_eq!(None, scope_key(&StoreScope::Global, &cwd));
+        assert_eq!(
+            Some(dir.to_string_lossy().to_string()),
+            scope_key(&StoreScope::Project, &cwd)
+        );
+        assert_eq!(
+            Some(cwd.to_string_lossy().to_string()),
+            scope_key(&StoreScope::Directory, &cwd)
+        );
 
-    // Write the size of each tuple for indexing
-    for data in &serialized_data {
-        let size = data.len() as IndexOffset;
-        total_size += size;
-        offsets.push(total_size);
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    let encoded: Vec<u8> = bincode::serialize(&offsets).expect("cannot serialize");
-    // debug!("try to write indexes {offsets:?} encoded as: {encoded:?}");
+    #[test]
+    fn test_scoped_path() {
+        let dir = "/home/me/.local/state/cgvg";
+        let path = scoped_path(dir, "/home/me/.cgvg.match", "/src/project");
+        assert!(path.starts_with("/home/me/.local/state/cgvg/"), "{path}");
+        assert!(path.ends_with("/cgvg.match"), "{path}");
+        assert_eq!(path, scoped_path(dir, "~/.cgvg.match", "/src/project"));
+        assert_ne!(path, scoped_path(dir, "~/.cgvg.match", "/src/other"));
+        assert_eq!(
+            "/state/af63dc4c8601ec8c/history",
+            scoped_path("/state", "/tmp/history", "a")
+        );
+    }
 
-    index.write_all(&encoded).expect("cannot write index");
-    index.sync_all().expect("sync index");
+    #[test]
+    fn test_archive_search() {
+        let dir = env::temp_dir().join(format!("cgvg-history-{}", process::id()));
+        let dir = dir.to_string_lossy().to_string();
+        let (file, index) = tmp_paths("archive");
 
-    // Write the actual data
-    for data in &serialized_data {
-        file.write_all(data).expect("cannot write data");
+        // Nothing to archive yet
+        archive_search(&dir, &file, &index, 2).unwrap();
+        assert!(history_entries(&dir).unwrap().is_empty());
+
+        for timestamp in [10, 30, 20] {
+            let info = SearchInfo {
+                pattern: Some(format!("search {timestamp}")),
+                timestamp,
+                ..SearchInfo::default()
+            };
+            let mut writer = StoreWriter::create(&file, &index, &info).unwrap();
+            writer
+                .push(&Index::new(b"a.rs".to_vec(), timestamp as u32))
+                .unwrap();
+            writer.finish().unwrap();
+            archive_search(&dir, &file, &index, 2).unwrap();
+        }
+        // The last store is kept in place, and archived once
+        assert!(fs::metadata(&file).is_ok());
+        archive_search(&dir, &file, &index, 3).unwrap();
+        assert_eq!(2, history_entries(&dir).unwrap().len());
+
+        // The oldest search is pruned, the newest comes first
+        let entries = history_entries(&dir).unwrap();
+        let patterns: Vec<Option<String>> = entries
+            .iter()
+            .map(|entry| load_info(&entry.match_file).unwrap().pattern)
+            .collect();
+        assert_eq!(
+            vec![
+                Some(String::from("search 30")),
+                Some(String::from("search 20"))
+            ],
+            patterns
+        );
+        assert_eq!(
+            20,
+            load(0, &entries[1].match_file, &entries[1].index_file)
+                .unwrap()
+                .line
+        );
+        // The store of the search 20 is not a previous search
+        assert_eq!(entries[..1], previous_searches(&dir, &file).unwrap()[..]);
+
+        // Searches started in the same millisecond are both kept
+        for _ in 0..2 {
+            let info = SearchInfo {
+                timestamp: 40,
+                ..SearchInfo::default()
+            };
+            StoreWriter::create(&file, &index, &info)
+                .unwrap()
+                .finish()
+                .unwrap();
+            archive_search(&dir, &file, &index, 3).unwrap();
+        }
+        let timestamps: Vec<u64> = history_entries(&dir)
+            .unwrap()
+            .iter()
+            .map(|entry| load_info(&entry.match_file).unwrap().timestamp)
+            .collect();
+        assert_eq!(vec![40, 40, 30], timestamps);
+        assert_eq!(2, previous_searches(&dir, &file).unwrap().len());
+
+        archive_search(&dir, &file, &index, 0).unwrap();
+        assert!(history_entries(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(format!("{file}.lock")).unwrap();
+        fs::remove_file(file).unwrap();
+        fs::remove_file(index).unwrap();
     }
 
-    file.sync_all().expect("cannot sync");
-}
+    #[test]
+    fn test_find_executable() {
+        use std::os::unix::fs::PermissionsExt;
 
-pub fn expand_path<'a>(path: &'a str) -> Result<String, String> {
-    // Yuck using sh to expand the path to handle path constructed with ~ or variabale ($HOME)
-    // We might consider one of these options: https://blog.liw.fi/posts/2021/10/12/tilde-expansion-crates/ (a bit outdated though)
-    let expand_tild = Command::new("sh")
-        .arg("-c")
-        .arg(format!("echo {}", path))
-        .output();
+        let dir = env::temp_dir().join(format!("cgvg-path-{}", process::id()));
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        let program = dir.join("bin").join("cgvg-test");
+        fs::write(&program, "#!/bin/sh\n").unwrap();
+        fs::write(dir.join("cgvg-test"), "").unwrap();
+
+        let search_path = env::join_paths([dir.clone(), dir.join("bin")]).unwrap();
+        let program = program.to_string_lossy().to_string();
+
+        // The file of the first directory is not executable
+        assert!(find_executable_in("cgvg-test", &search_path).is_err());
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            Ok(program.clone()),
+            find_executable_in("cgvg-test", &search_path)
+        );
+        assert_eq!(
+            Ok(program.clone()),
+            find_executable_in(&program, &search_path)
+        );
+
+        let err = find_executable_in("cgvg-missing", &search_path).unwrap_err();
+        assert!(
+            err.contains(&dir.join("bin").to_string_lossy().to_string()),
+            "{err}"
+        );
+        assert!(find_executable_in("/cgvg/missing", &search_path).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expand_path() {
+        env::set_var("CGVG_TEST_DIR", "/tmp/cg vg");
+        let home = env::var("HOME").unwrap();
+
+        assert_eq!(
+            format!("{home}/.cgvg.match"),
+            expand_path("~/.cgvg.match").unwrap()
+        );
+        assert_eq!(home, expand_path("~").unwrap());
+        assert_eq!("/tmp/cg vg/a", expand_path("$CGVG_TEST_DIR/a").unwrap());
+        assert_eq!("/tmp/cg vg_a", expand_path("${CGVG_TEST_DIR}_a").unwrap());
+        // Only ~, ~user and variables are expanded
+        assert_eq!("/tmp/a b;$(ls)/$", expand_path("/tmp/a b;$(ls)/$").unwrap());
+        assert_eq!("a~/b", expand_path("a~/b").unwrap());
+
+        assert!(expand_path("$CGVG_TEST_UNSET_VARIABLE/a").is_err());
+        assert!(expand_path("${CGVG_TEST_DIR").is_err());
+        assert!(expand_path("${}").is_err());
+        assert!(expand_path("~cgvg_unknown_user/a").is_err());
+    }
 
-    let p = String::from_utf8(expand_tild.expect("Command failed to run").stdout)
-        .unwrap()
-        .split_whitespace()
-        .map(|s| String::from(s))
-        .next()
+    #[test]
+    fn test_expand_user() {
+        let root = user_home("root").unwrap();
+        assert_eq!(format!("{root}/a"), expand_path("~root/a").unwrap());
+    }
+
+    #[test]
+    fn test_index_with_submatch() {
+        let index = Index::with_submatch(b"a.rs".to_vec(), 3, "let café = x;".as_bytes(), 12, 13);
+        assert_eq!((12, 13), (index.column, index.byte_column));
+        assert_eq!(b"x", index.submatch.as_slice());
+        assert_eq!(vec![(12, 13)], index.ranges);
+
+        // Offsets past the end of the line are clamped
+        let index = Index::with_submatch(b"a.rs".to_vec(), 3, b"ab", 10, 10);
+        assert_eq!((3, 3), (index.column, index.byte_column));
+    }
+
+    #[test]
+    fn test_save_quickfix() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tuples = vec![
+            Index::with_submatch(b"src/main.rs".to_vec(), 3, b"    foo(bar)\n", 4, 4),
+            Index::with_submatch(b"caf\xe9.rs".to_vec(), 7, b"foo\nbar\n", 0, 0),
+        ];
+
+        let dir = env::temp_dir().join(format!("cgvg-quickfix-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let file = save_quickfix(&tuples, &dir).unwrap();
+        assert_eq!(
+            b"src/main.rs:3:5:    foo(bar)\ncaf\xe9.rs:7:1:foo\n".to_vec(),
+            fs::read(&file).unwrap()
+        );
+        assert_eq!(
+            0o600,
+            fs::metadata(&file).unwrap().permissions().mode() & 0o777
+        );
+
+        // The files of the previous calls are removed once they are old
+        let next = save_quickfix(&tuples, &dir).unwrap();
+        assert_ne!(file, next);
+        assert!(fs::metadata(&file).is_ok());
+        File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(time::SystemTime::now() - QUICKFIX_FILE_AGE * 2)
+            .unwrap();
+        let last = save_quickfix(&tuples, &dir).unwrap();
+        assert!(fs::metadata(&file).is_err());
+        assert!(fs::metadata(&next).is_ok());
+
+        fs::remove_file(next).unwrap();
+        fs::remove_file(last).unwrap();
+        fs::remove_dir(dir).unwrap();
+    }
+
+    #[test]
+    fn test_binary_roundtrip() {
+        let (match_file, index_file) = tmp_paths("binary");
+        let tuples = vec![
+            Index::new(b"src/main.rs".to_vec(), 12),
+            Index::with_submatch(b"a path with spaces.rs".to_vec(), 1, b"let x = 1;", 4, 4),
+            Index::new(b"caf\xe9.md".to_vec(), 4242),
+            // Second submatch of the line of the entry 1
+            Index {
+                text: vec![],
+                ranges: vec![],
+                line_entry: Some(1),
+                ..Index::with_submatch(b"a path with spaces.rs".to_vec(), 1, b"let x = 1;", 8, 9)
+            },
+        ];
+
+        save_binary(tuples.clone(), &match_file, &index_file).unwrap();
+        assert!(is_binary_store(&match_file).unwrap());
+
+        for (i, tuple) in tuples[..3].iter().enumerate() {
+            assert_eq!(
+                *tuple,
+                load_binary(i as u32, &match_file, &index_file).unwrap()
+            );
+        }
+        // The text and the ranges are taken from the first entry of the line
+        let index = load_binary(3, &match_file, &index_file).unwrap();
+        assert_eq!(
+            (b"let x = 1;".as_slice(), b"1".as_slice()),
+            (&index.text[..], &index.submatch[..])
+        );
+        assert_eq!(vec![(4, 4)], index.ranges);
+
+        assert!(matches!(
+            load_binary(4, &match_file, &index_file),
+            Err(CgVgError::LoadIndexOob(4, 4))
+        ));
+        assert_eq!(tuples, load_all(&match_file, &index_file).unwrap());
+
+        fs::remove_file(format!("{match_file}.lock")).unwrap();
+        fs::remove_file(match_file).unwrap();
+        fs::remove_file(index_file).unwrap();
+    }
+
+    #[test]
+    fn test_store_writer_flush() {
+        let (match_file, index_file) = tmp_paths("writer");
+        let info = SearchInfo {
+            pattern: Some(String::from("fo+")),
+            cwd: Some(String::from("/src")),
+            args: vec![String::from("fo+"), String::from("src")],
+            timestamp: 1_700_000_000_000,
+            per_submatch: false,
+        };
+        save_binary(vec![], &match_file, &index_file).unwrap();
+
+        // The temporary files of an interrupted writer are removed
+        let writer = StoreWriter::create(&match_file, &index_file, &info).unwrap();
+        drop(writer);
+        assert!(fs::metadata(temp_path(&match_file)).is_err());
+        assert!(fs::metadata(temp_path(&index_file)).is_err());
+        // pid_max is at most 2^22, the process of this file cannot be running
+        let stale = format!("{match_file}.{}.tmp", 1 << 23);
+        fs::write(&stale, b"").unwrap();
+
+        let mut writer = StoreWriter::create(&match_file, &index_file, &info).unwrap();
+        assert!(fs::metadata(&stale).is_err());
+
+        // The previous store is replaced at the first flush
+        writer.push(&Index::new(b"src/a.rs".to_vec(), 1)).unwrap();
+        assert_eq!(0, store_len(&match_file, &index_file).unwrap());
+        writer.flush().unwrap();
+        assert!(fs::metadata(temp_path(&match_file)).is_err());
+        writer.push(&Index::new(b"src/b.rs".to_vec(), 2)).unwrap();
+
+        // Only the flushed entries are visible, as if cg was interrupted here
+        assert_eq!(
+            Index::new(b"src/a.rs".to_vec(), 1),
+            load_binary(0, &match_file, &index_file).unwrap()
+        );
+        assert!(matches!(
+            load_binary(1, &match_file, &index_file),
+            Err(CgVgError::LoadIndexOob(1, 1))
+        ));
+
+        assert_eq!(2, writer.finish().unwrap());
+        assert_eq!(info, load_info(&match_file).unwrap());
+        assert_eq!(
+            Index::new(b"src/b.rs".to_vec(), 2),
+            load_binary(1, &match_file, &index_file).unwrap()
+        );
+
+        fs::remove_file(format!("{match_file}.lock")).unwrap();
+        fs::remove_file(match_file).unwrap();
+        fs::remove_file(index_file).unwrap();
+    }
+
+    #[test]
+    fn test_store_generation() {
+        let (match_file, index_file) = tmp_paths("generation");
+        let (other_file, other_index) = tmp_paths("generation-other");
+        save_binary(
+            vec![Index::new(b"a.rs".to_vec(), 1)],
+            &match_file,
+            &index_file,
+        )
+        .unwrap();
+        save_binary(
+            vec![Index::new(b"b.rs".to_vec(), 2)],
+            &other_file,
+            &other_index,
+        )
         .unwrap();
 
-    Ok(p)
+        // The index of another store is detected, even with the same number of entries
+        fs::copy(&other_index, &index_file).unwrap();
+        assert!(matches!(
+            load(0, &match_file, &index_file),
+            Err(CgVgError::StoreGeneration(_))
+        ));
+        assert!(matches!(
+            store_len(&match_file, &index_file),
+            Err(CgVgError::StoreGeneration(_))
+        ));
+        assert!(matches!(
+            load_all(&match_file, &index_file),
+            Err(CgVgError::StoreGeneration(_))
+        ));
+
+        for file in [match_file, index_file, other_file, other_index] {
+            fs::remove_file(&file).unwrap();
+            fs::remove_file(format!("{file}.lock")).ok();
+        }
+    }
+
+    #[test]
+    fn test_migrate_text() {
+        let (match_file, index_file) = tmp_paths("migrate");
+        let tuples = vec![
+            Index::new(b"src/lib.rs".to_vec(), 1),
+            Index::new(b"caf\xe9.rs".to_vec(), 7),
+        ];
+
+        save_text(tuples.clone(), &match_file);
+        assert!(!is_binary_store(&match_file).unwrap());
+        assert_eq!(tuples[1], load_text(1, &match_file).unwrap());
+
+        // The migration fails without panicking when the store cannot be written
+        let unwritable = env::temp_dir().join(format!("cgvg-missing-{}", process::id()));
+        let unwritable = unwritable.join("index").to_string_lossy().to_string();
+        assert!(matches!(
+            load(1, &match_file, &unwritable),
+            Err(CgVgError::Io(_))
+        ));
+        assert!(!is_binary_store(&match_file).unwrap());
+
+        assert_eq!(tuples[1], load(1, &match_file, &index_file).unwrap());
+        assert_eq!(2, store_len(&match_file, &index_file).unwrap());
+        assert!(is_binary_store(&match_file).unwrap());
+        assert_eq!(tuples[0], load(0, &match_file, &index_file).unwrap());
+
+        fs::remove_file(format!("{match_file}.lock")).unwrap();
+        fs::remove_file(match_file).unwrap();
+        fs::remove_file(index_file).unwrap();
+    }
 }
@@ -0,0 +1,334 @@
+use crate::common::{expand_path, scope_key, scoped_path, scopes_dir};
+use log::debug;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::*;
+
+/// Configuration of cg and vg, read from `$XDG_CONFIG_HOME/cgvg/config.toml`.
+///
+/// Every field is optional, the command line options take precedence over the configuration.
+///
+/// ```toml
+/// match_file = "~/.cgvg.match"
+/// index_file = "~/.cgvg.idx"
+/// history_dir = "~/.cgvg.history"
+/// history_size = 10
+/// scope = "global"
+///
+/// [cg]
+/// rg = "rg"
+/// rg_args = ["--sort", "path", "--smart-case"]
+/// max_text_size = 500
+/// stats = false
+/// color = "auto"
+/// view = "grouped"
+/// per_submatch = false
+///
+/// [colors]
+/// path = "red"
+/// index = "cyan"
+/// line_number = "bright magenta"
+/// match = "bold blue"
+/// context = "dimmed"
+/// truncated = "red"
+///
+/// [vg]
+/// editor = "nvim"
+/// instance = "spawn"
+/// wait = false
+///
+/// [vg.editors.lite-xl]
+/// format = "{EDITOR} {PATH}:{LINE}"
+/// multi_format = "{EDITOR} {PATH}:{LINE}"
+/// quickfix_format = "{EDITOR} {QUICKFIX}"
+/// wait = "--wait"
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Place match file of rgvg
+    pub match_file: String,
+    /// path to index state file of rgvg
+    pub index_file: String,
+    /// Directory keeping the previous searches
+    pub history_dir: String,
+    /// Number of previous searches kept in the history, 0 disables the history
+    pub history_size: usize,
+    /// Which searches share the match file and the history. The stores of the other scopes than
+    /// global are in `$XDG_STATE_HOME/cgvg`
+    pub scope: StoreScope,
+    pub cg: CgConfig,
+    pub vg: VgConfig,
+    pub colors: ColorsConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CgConfig {
+    /// Binary name of rg, or path
+    pub rg: String,
+    /// Arguments always given to rg, before the ones of the command line. `--json` is always
+    /// added by cg.
+    pub rg_args: Vec<String>,
+    /// Matched lines longer than this are not printed, 0 prints all the lines.
+    pub max_text_size: u32,
+    /// Print statistics of the search
+    pub stats: bool,
+    /// When to color the output
+    pub color: ColorMode,
+    /// How to print the results
+    pub view: ViewKind,
+    /// Give each submatch of a line its own selection index
+    pub per_submatch: bool,
+}
+
+/// How cg prints the results.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ViewKind {
+    /// Matches grouped under the name of their file
+    #[default]
+    Grouped,
+    /// One line per match with the index, path, line number and text in aligned columns
+    Columns,
+}
+
+/// When to color the output.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    /// Colors when stdout is a terminal and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Which searches share the store, cg and vg resolve the same store from where they are run.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreScope {
+    /// One store for all the searches
+    #[default]
+    Global,
+    /// One store per git repository, or per directory outside of a repository
+    Project,
+    /// One store per working directory
+    Directory,
+    /// One store per tmux pane or terminal
+    Terminal,
+}
+
+/// How vg opens the editor.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum InstanceMode {
+    /// Start a new instance of the editor
+    #[default]
+    Spawn,
+    /// Open the selections in a running instance when one can be found
+    Reuse,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct VgConfig {
+    /// Editor used when `--editor` is not given, before looking at `$EDITOR`.
+    pub editor: Option<String>,
+    /// Open the selections in a running instance of the editor, or start a new one
+    pub instance: InstanceMode,
+    /// Give their wait flag to the editors
+    pub wait: bool,
+    /// Rules of the editors, indexed by editor name. They take precedence over the builtin rules.
+    pub editors: HashMap<String, EditorConfig>,
+}
+
+/// Rule to open an editor, the missing formats are taken from the builtin rule of the editor.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct EditorConfig {
+    /// Format to open one selection. See `vg --help` for the placeholders.
+    pub format: Option<String>,
+    /// Format to open several selections in one call. The words using the placeholders of a
+    /// selection are repeated for each selection. Editors without one are opened once per
+    /// selection.
+    pub multi_format: Option<String>,
+    /// Format to open the quickfix file of `vg --quickfix`, the placeholder `{QUICKFIX}` is the
+    /// path of the file.
+    pub quickfix_format: Option<String>,
+    /// Flag making the editor wait for the files to be closed, given with `vg --wait`
+    pub wait: Option<String>,
+}
+
+/// Theme of the match view. Each role is a color name of the `colored` crate (e.g. `"red"`,
+/// `"bright blue"`), optionally with the attributes `bold`, `dimmed`, `italic` and `underline`.
+///
+/// The roles can also be overridden with the `CG_COLORS` environment variable.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColorsConfig {
+    pub path: String,
+    pub index: String,
+    pub line_number: String,
+    #[serde(rename = "match")]
+    pub submatch: String,
+    pub context: String,
+    pub truncated: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            match_file: String::from("~/.cgvg.match"),
+            index_file: String::from("~/.cgvg.idx"),
+            history_dir: String::from("~/.cgvg.history"),
+            history_size: 10,
+            scope: StoreScope::Global,
+            cg: CgConfig::default(),
+            vg: VgConfig::default(),
+            colors: ColorsConfig::default(),
+        }
+    }
+}
+
+impl Default for CgConfig {
+    fn default() -> Self {
+        CgConfig {
+            rg: String::from("rg"),
+            rg_args: vec![String::from("--sort"), String::from("path")],
+            max_text_size: 500,
+            stats: false,
+            color: ColorMode::Auto,
+            view: ViewKind::Grouped,
+            per_submatch: false,
+        }
+    }
+}
+
+impl Default for ColorsConfig {
+    fn default() -> Self {
+        ColorsConfig {
+            path: String::from("red"),
+            index: String::from("cyan"),
+            line_number: String::from("bright magenta"),
+            submatch: String::from("bold blue"),
+            context: String::from("dimmed"),
+            truncated: String::from("red"),
+        }
+    }
+}
+
+impl Config {
+    /// Path of the configuration file, `$XDG_CONFIG_HOME/cgvg/config.toml` or
+    /// `~/.config/cgvg/config.toml`.
+    pub fn path() -> Option<PathBuf> {
+        let config_home = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+
+        Some(config_home.join("cgvg").join("config.toml"))
+    }
+
+    /// Load the configuration file, the default configuration is returned if there is none.
+    pub fn load() -> Result<Config, String> {
+        match Config::path() {
+            Some(path) if path.exists() => Config::from_file(&path),
+            _ => Ok(Config::default()),
+        }
+    }
+
+    pub fn from_file(path: &path::Path) -> Result<Config, String> {
+        debug!("loading configuration from {path:?}");
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read configuration {}: {e}", path.display()))?;
+
+        Config::parse(&content)
+            .map_err(|e| format!("invalid configuration {}: {e}", path.display()))
+    }
+
+    /// Expanded paths of the match file, the index file and the history directory.
+    ///
+    /// The paths given on the command line are used as is, the ones of the configuration are
+    /// moved to the directory of the `scope`, see [`scoped_path`].
+    pub fn store_paths(
+        &self,
+        match_file: Option<String>,
+        index_file: Option<String>,
+        scope: Option<StoreScope>,
+    ) -> Result<(String, String, String), String> {
+        let cwd =
+            env::current_dir().map_err(|e| format!("cannot read the current directory: {e}"))?;
+        let key = scope_key(scope.as_ref().unwrap_or(&self.scope), &cwd);
+        debug!("store scope key: {key:?}");
+
+        let resolve = |explicit: Option<String>, configured: &str| {
+            let path = match (explicit, &key) {
+                (Some(path), _) => path,
+                (None, Some(key)) => scoped_path(&scopes_dir(), configured, key),
+                (None, None) => configured.to_string(),
+            };
+            expand_path(&path)
+        };
+
+        Ok((
+            resolve(match_file, &self.match_file)?,
+            resolve(index_file, &self.index_file)?,
+            resolve(None, &self.history_dir)?,
+        ))
+    }
+
+    pub fn parse(content: &str) -> Result<Config, String> {
+        toml::from_str(content).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+            match_file = "/tmp/match"
+
+            [cg]
+            rg_args = ["--smart-case"]
+
+            color = "never"
+
+            [colors]
+            match = "green"
+
+            [vg.editors.lite-xl]
+            format = "{EDITOR} {PATH}"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!("/tmp/match", config.match_file);
+        // Missing fields keep their default value
+        assert_eq!("~/.cgvg.idx", config.index_file);
+        assert_eq!("rg", config.cg.rg);
+        assert_eq!(vec!["--smart-case"], config.cg.rg_args);
+        assert_eq!(500, config.cg.max_text_size);
+        assert_eq!(ColorMode::Never, config.cg.color);
+        assert_eq!("green", config.colors.submatch);
+        assert_eq!("red", config.colors.path);
+        assert_eq!(
+            Some("{EDITOR} {PATH}"),
+            config.vg.editors["lite-xl"].format.as_deref()
+        );
+        assert_eq!(None, config.vg.editors["lite-xl"].wait);
+
+        assert!(Config::parse("unknown_option = 1").is_err());
+        // The formats are set in the rules of the editors
+        assert!(Config::parse("[vg.formats]\nhx = \"{EDITOR} {PATH}\"").is_err());
+        assert_eq!(
+            Config::default().cg.rg_args,
+            Config::parse("").unwrap().cg.rg_args
+        );
+    }
+}
@@ -0,0 +1,329 @@
+use rgvg::common::find_executable;
+use rgvg::config::EditorConfig;
+use std::*;
+
+/// Formats to open an editor, see `vg --help` for the placeholders.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Formats {
+    /// Format opening one selection
+    pub open: Option<String>,
+    /// Format opening several selections in one call
+    pub multi: Option<String>,
+    /// Format opening the quickfix file
+    pub quickfix: Option<String>,
+    /// Flag making the editor wait for the files to be closed before exiting
+    pub wait: Option<String>,
+}
+
+impl Formats {
+    /// Use the formats of `fallback` for the ones that are not set.
+    pub fn or(self, fallback: Option<Formats>) -> Formats {
+        let fallback = fallback.unwrap_or_default();
+        Formats {
+            open: self.open.or(fallback.open),
+            multi: self.multi.or(fallback.multi),
+            quickfix: self.quickfix.or(fallback.quickfix),
+            wait: self.wait.or(fallback.wait),
+        }
+    }
+}
+
+impl From<&EditorConfig> for Formats {
+    fn from(config: &EditorConfig) -> Self {
+        Formats {
+            open: config.format.clone(),
+            multi: config.multi_format.clone(),
+            quickfix: config.quickfix_format.clone(),
+            wait: config.wait.clone(),
+        }
+    }
+}
+
+/// Builtin rule of an editor.
+struct EditorRule {
+    /// Names of the binary of the editor
+    names: &'static [&'static str],
+    open: &'static str,
+    /// Format opening several selections in one call, when the editor can do it
+    multi: Option<&'static str>,
+    quickfix: Option<&'static str>,
+    wait: Option<&'static str>,
+}
+
+/// Editors known by vg.
+static EDITORS: &[EditorRule] = &[
+    EditorRule {
+        names: &["vim", "nvim"],
+        // cursor() takes a byte column
+        open: r#"{EDITOR} "+call cursor({LINE},{BYTE_COLUMN})" {PATH}"#,
+        multi: None,
+        quickfix: Some("{EDITOR} -q {QUICKFIX}"),
+        wait: None,
+    },
+    EditorRule {
+        names: &["vi"],
+        open: "{EDITOR} +{LINE} {PATH}",
+        multi: None,
+        quickfix: None,
+        wait: None,
+    },
+    EditorRule {
+        names: &["emacs"],
+        open: "{EDITOR} +{LINE}:{COLUMN} {PATH}",
+        multi: Some("{EDITOR} +{LINE}:{COLUMN} {PATH}"),
+        // grep-mode finds the files from the directory of the buffer, it is set back to the
+        // directory of emacs
+        quickfix: Some(
+            r#"{EDITOR} --eval "(let ((dir default-directory)) (find-file \"{QUICKFIX}\") (setq default-directory dir) (grep-mode))""#,
+        ),
+        wait: None,
+    },
+    EditorRule {
+        names: &["emacsclient"],
+        open: "{EDITOR} +{LINE}:{COLUMN} {PATH}",
+        multi: Some("{EDITOR} +{LINE}:{COLUMN} {PATH}"),
+        quickfix: None,
+        wait: None,
+    },
+    EditorRule {
+        names: &["code", "codium"],
+        open: "{EDITOR} -g {PATH}:{LINE}:{COLUMN}",
+        multi: Some("{EDITOR} -g {PATH}:{LINE}:{COLUMN}"),
+        quickfix: None,
+        wait: Some("--wait"),
+    },
+    EditorRule {
+        names: &["hx", "helix"],
+        open: "{EDITOR} {PATH}:{LINE}:{COLUMN}",
+        multi: Some("{EDITOR} {PATH}:{LINE}:{COLUMN}"),
+        quickfix: None,
+        wait: None,
+    },
+    EditorRule {
+        names: &["kak"],
+        open: "{EDITOR} +{LINE}:{COLUMN} {PATH}",
+        multi: None,
+        quickfix: None,
+        wait: None,
+    },
+    EditorRule {
+        names: &["micro"],
+        open: "{EDITOR} +{LINE}:{COLUMN} {PATH}",
+        multi: None,
+        quickfix: None,
+        wait: None,
+    },
+    EditorRule {
+        names: &["nano"],
+        open: "{EDITOR} +{LINE},{COLUMN} {PATH}",
+        multi: Some("{EDITOR} +{LINE},{COLUMN} {PATH}"),
+        quickfix: None,
+        wait: None,
+    },
+    EditorRule {
+        names: &["subl", "sublime_text"],
+        open: "{EDITOR} {PATH}:{LINE}:{COLUMN}",
+        multi: Some("{EDITOR} {PATH}:{LINE}:{COLUMN}"),
+        quickfix: None,
+        wait: Some("--wait"),
+    },
+    EditorRule {
+        names: &["zed", "zeditor"],
+        open: "{EDITOR} {PATH}:{LINE}:{COLUMN}",
+        multi: Some("{EDITOR} {PATH}:{LINE}:{COLUMN}"),
+        quickfix: None,
+        wait: Some("--wait"),
+    },
+    EditorRule {
+        names: &["idea", "idea.sh"],
+        open: "{EDITOR} --line {LINE} --column {COLUMN} {PATH}",
+        multi: None,
+        quickfix: None,
+        wait: Some("--wait"),
+    },
+    EditorRule {
+        names: &["kate"],
+        open: "{EDITOR} --line {LINE} --column {COLUMN} {PATH}",
+        multi: None,
+        quickfix: None,
+        wait: Some("--block"),
+    },
+    EditorRule {
+        names: &["gedit"],
+        open: "{EDITOR} +{LINE}:{COLUMN} {PATH}",
+        multi: None,
+        quickfix: None,
+        wait: Some("--wait"),
+    },
+];
+
+/// Names of the editors known by vg.
+pub fn known_editors() -> Vec<&'static str> {
+    EDITORS
+        .iter()
+        .flat_map(|rule| rule.names.iter().copied())
+        .collect()
+}
+
+/// Builtin formats starting a new instance of the editor.
+pub fn spawn_formats(editor_name: &str) -> Option<Formats> {
+    let rule = EDITORS
+        .iter()
+        .find(|rule| rule.names.contains(&editor_name))?;
+
+    Some(Formats {
+        open: Some(rule.open.to_string()),
+        multi: rule.multi.map(String::from),
+        quickfix: rule.quickfix.map(String::from),
+        wait: rule.wait.map(String::from),
+    })
+}
+
+/// Builtin formats opening the selections in a running instance of the editor, `None` if there
+/// is no instance to reuse.
+///
+/// nvim is reached through the socket given with `--server`, or the `$NVIM` variable set in its
+/// terminals. emacs is reached with `emacsclient` when its server is running, and code with
+/// `--reuse-window`.
+pub fn reuse_formats(editor_name: &str, server: Option<String>) -> Option<Formats> {
+    match editor_name {
+        "nvim" => {
+            let server = server.or_else(|| env::var("NVIM").ok())?;
+            let remote = format!("{{EDITOR}} --server {}", shlex::try_quote(&server).ok()?);
+            // --remote-send types keys, only the numbers of the cursor are sent with it. The files
+            // are opened with --remote, which escapes them, from the root since the directory of
+            // nvim might not be the one of vg. The quickfix list is read from the buffer of the
+            // quickfix file, which is then closed.
+            Some(Formats {
+                open: Some(format!(
+                    r#"{remote} --remote {{ABSOLUTE_PATH}} && {remote} --remote-send "<C-\><C-N>:call cursor({{LINE}},{{BYTE_COLUMN}})<CR>""#
+                )),
+                multi: None,
+                quickfix: Some(format!(
+                    r#"{remote} --remote {{QUICKFIX}} && {remote} --remote-send "<C-\><C-N>:cgetbuffer<Bar>bwipeout<CR>:copen<CR>""#
+                )),
+                wait: None,
+            })
+        }
+        "emacs" => {
+            find_executable("emacsclient").ok()?;
+            emacs_server_socket()?;
+
+            // The server does not know the directory of vg, it is given to grep-mode
+            let cwd = env::current_dir().ok()?;
+            let quickfix = format!(
+                r#"(progn (find-file "{{QUICKFIX}}") (setq default-directory {:?}) (grep-mode))"#,
+                format!("{}/", cwd.to_str()?)
+            );
+            Some(Formats {
+                open: Some(String::from("emacsclient -n +{LINE}:{COLUMN} {PATH}")),
+                multi: Some(String::from("emacsclient -n +{LINE}:{COLUMN} {PATH}")),
+                quickfix: Some(format!(
+                    "emacsclient -n --eval {}",
+                    shlex::try_quote(&quickfix).ok()?
+                )),
+                wait: None,
+            })
+        }
+        "code" | "codium" => Some(Formats {
+            open: Some(String::from(
+                "{EDITOR} --reuse-window -g {PATH}:{LINE}:{COLUMN}",
+            )),
+            multi: Some(String::from(
+                "{EDITOR} --reuse-window -g {PATH}:{LINE}:{COLUMN}",
+            )),
+            quickfix: None,
+            wait: Some(String::from("--wait")),
+        }),
+        _ => None,
+    }
+}
+
+/// Socket of the running emacs server, looked up like `emacsclient` does: `$EMACS_SOCKET_NAME`,
+/// then `$XDG_RUNTIME_DIR/emacs/server` and `$TMPDIR/emacs<uid>/server`.
+fn emacs_server_socket() -> Option<path::PathBuf> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let uid = unsafe { libc::getuid() };
+    let candidates = [
+        env::var_os("EMACS_SOCKET_NAME").map(path::PathBuf::from),
+        env::var_os("XDG_RUNTIME_DIR").map(|dir| path::Path::new(&dir).join("emacs/server")),
+        Some(env::temp_dir().join(format!("emacs{uid}/server"))),
+    ];
+
+    candidates
+        .into_iter()
+        .flatten()
+        .find(|socket| fs::metadata(socket).is_ok_and(|meta| meta.file_type().is_socket()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spawn_formats() {
+        let hx = spawn_formats("hx").unwrap();
+        assert_eq!(Some("{EDITOR} {PATH}:{LINE}:{COLUMN}"), hx.open.as_deref());
+        assert_eq!(hx, spawn_formats("helix").unwrap());
+        assert_eq!(
+            Some("--block"),
+            spawn_formats("kate").unwrap().wait.as_deref()
+        );
+        assert!(spawn_formats("ed").is_none());
+        assert!(known_editors().contains(&"subl"));
+
+        // Every builtin format is valid
+        for name in known_editors() {
+            let formats = spawn_formats(name).unwrap();
+            for format in [formats.open, formats.multi, formats.quickfix]
+                .iter()
+                .flatten()
+            {
+                assert!(shlex::split(format).is_some(), "{name}: {format}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_reuse_nvim() {
+        let formats = reuse_formats("nvim", Some(String::from("/run/nvim 1.sock"))).unwrap();
+        let words = shlex::split(formats.open.as_deref().unwrap()).unwrap();
+        let commands: Vec<&[String]> = words.split(|word| word == "&&").collect();
+
+        // The path is never typed as keys in nvim
+        assert_eq!(
+            [
+                "{EDITOR}",
+                "--server",
+                "/run/nvim 1.sock",
+                "--remote",
+                "{ABSOLUTE_PATH}"
+            ],
+            commands[0]
+        );
+        assert_eq!("--remote-send", commands[1][3]);
+        assert!(!commands[1][4].contains("PATH}"), "{}", commands[1][4]);
+
+        let words = shlex::split(formats.quickfix.as_deref().unwrap()).unwrap();
+        let commands: Vec<&[String]> = words.split(|word| word == "&&").collect();
+        assert_eq!(["--remote", "{QUICKFIX}"], commands[0][3..]);
+        assert!(!commands[1][4].contains("{QUICKFIX}"), "{}", commands[1][4]);
+    }
+
+    #[test]
+    fn test_formats_or() {
+        let config = EditorConfig {
+            format: Some(String::from("{EDITOR} {PATH}")),
+            ..EditorConfig::default()
+        };
+        let formats = Formats::from(&config).or(spawn_formats("code"));
+
+        assert_eq!(Some("{EDITOR} {PATH}"), formats.open.as_deref());
+        assert_eq!(
+            Some("{EDITOR} -g {PATH}:{LINE}:{COLUMN}"),
+            formats.multi.as_deref()
+        );
+        assert_eq!(Some("--wait"), formats.wait.as_deref());
+    }
+}
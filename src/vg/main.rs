@@ -1,47 +1,275 @@
 use clap::Parser;
 use log::debug;
-use rgvg::common::{expand_path, load_text, CgVgError};
+use rgvg::common::{find_executable, previous_searches, save_quickfix, CgVgError, Index, Store};
+use rgvg::config::{Config, InstanceMode, StoreScope};
 use std::env;
-use std::fs;
+use std::os::unix::ffi::OsStrExt;
 use std::process::{Command, ExitCode};
+use std::{fs, path};
 
-use std::ffi::CString;
+use std::ffi::{CString, OsStr};
 use std::ptr;
 
+mod editors;
+use editors::{known_editors, reuse_formats, spawn_formats, Formats};
+
 extern "C" {
     fn execvp(path: *const libc::c_char, argv: *const *const libc::c_char) -> libc::c_int;
 }
 
 /// vg edit code mathing previous rg research
 ///
-/// The program reads your $EDITOR environment variable.
-/// The editors vim, nvim, emacs, code, codium should be handled by default.
+/// The program reads your $VISUAL or $EDITOR environment variable, and falls back to vi. The
+/// variable can give arguments to the editor, like `EDITOR="code --wait"`.
+/// The editors vim, nvim, vi, emacs, code, codium, helix, kakoune, micro, nano, sublime, zed, idea,
+/// kate and gedit should be handled by default.
 /// If your editor is not in the liste you can use the --format option to describe the command line that shoul open your editor.
 ///
+/// Several selections are opened in one call when the editor can open several files at
+/// once (emacs, code, helix, nano, sublime, zed), in the quickfix list of vim and nvim, otherwise
+/// the editor is opened for each selection, one after the other.
+///
+/// Defaults of the options, and the formats of other editors, can be set in the configuration file
+/// `$XDG_CONFIG_HOME/cgvg/config.toml`.
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Args {
-    /// Selection number from previous rg command
-    seletion: u32,
+    /// Selection numbers from previous rg command: indexes (`3 7`), inclusive ranges (`10-15`) or
+    /// `all`. A first selection like `@-2` picks the search of the history, like `--search 2`.
+    #[arg(required_unless_present = "quickfix")]
+    selections: Vec<String>,
+    /// Open the selections of a previous search, 1 is the search before the last one. `cg
+    /// --history` lists the searches with their number.
+    #[arg(short, long)]
+    search: Option<usize>,
+    /// Open the selections, or all the matches when there is none, in the quickfix list of vim
+    /// (`vim -q`) or in a grep-mode buffer of emacs.
+    ///
+    /// The matches are written in a temporary file with the `path:line:column:text` format, the
+    /// placeholder {QUICKFIX} of the quickfix formats of the configuration is its path.
+    #[arg(short, long)]
+    quickfix: bool,
     /// Format to describe how to open your editor.
     /// Simple format to tune how `vg` will open your editor.
-    /// The format should use the placeholders: {LINE}, {EDITOR} and {PATH}.
+    /// The format should use the placeholders: {LINE}, {EDITOR} and {PATH}. {COLUMN} and
+    /// {BYTE_COLUMN} are the 1-based column of the match, in characters and in bytes. {DIR} and
+    /// {BASENAME} are the directory and the file name of the path, {ABSOLUTE_PATH} is the path
+    /// from the root, {MATCH} is the matched text and {PATTERN} the pattern given to rg.
     ///
-    /// The default for vim for instance is "{EDITOR} +{LINE} {PATH}".
-    /// It will be resolved as "nvim +21 /path/to/file"
+    /// The format is split in arguments like a shell would, quotes keep spaces in an argument.
+    /// Each word is one argument whatever the values of its placeholders, so paths with spaces
+    /// do not need to be quoted. A `&&` word separates commands that are run one after the other.
+    ///
+    /// The default for emacs for instance is "{EDITOR} +{LINE}:{COLUMN} {PATH}".
+    /// It will be resolved as "emacs +21:8 /path/to/file"
     #[arg(short, long)]
     format: Option<String>,
 
-    /// Specify the path to your editor
+    /// Specify the path to your editor, with its arguments
     #[arg(short, long)]
     editor: Option<String>,
+    /// Open the selections in a running instance of the editor (nvim, emacs with
+    /// emacsclient, code), or start a new one [default: spawn]
+    #[arg(long, value_enum)]
+    instance: Option<InstanceMode>,
+    /// Socket of the nvim instance to use, `$NVIM` is used by default in reuse mode
+    #[arg(long)]
+    server: Option<String>,
+    /// Make graphical editors (code, sublime, zed, idea, kate, gedit) wait for the files to be
+    /// closed, so the selections opened one after the other are seen one at a time
+    #[arg(short, long)]
+    wait: bool,
+
+    /// Which searches share the match file and the history, like for cg [default: global]
+    #[arg(long, value_enum)]
+    scope: Option<StoreScope>,
+    /// path to index state file of rgvg [default: ~/.cgvg.idx]
+    #[arg(short, long)]
+    index_file: Option<String>,
+    /// Place match file of rgvg [default: ~/.cgvg.match]
+    #[arg(short, long)]
+    match_file: Option<String>,
+}
+
+/// Parse the selections of the command line, `len` is the number of matches in the store.
+///
+/// The bounds are checked before the ranges are expanded, so a large range is not allocated.
+fn parse_selections(selections: &[String], len: u32) -> Result<Vec<u32>, String> {
+    let parse = |number: &str| {
+        number.parse::<u32>().map_err(|_| {
+            format!(
+                "Invalid selection {number:?}, expected a number, a range like `10-15` or `all`."
+            )
+        })
+    };
+    let check = |idx: u32| match idx < len {
+        true => Ok(idx),
+        false => Err(format!(
+            "Provided Index ({}) greater that number of possibilities ({}).",
+            idx, len
+        )),
+    };
+
+    let mut parsed = vec![];
+    for selection in selections {
+        match selection.split_once('-') {
+            _ if selection == "all" => {
+                if len == 0 {
+                    return Err(String::from("The search has no match to select."));
+                }
+                parsed.extend(0..len)
+            }
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    return Err(format!(
+                        "Invalid range {selection:?}, {start} is greater than {end}."
+                    ));
+                }
+                parsed.extend(start..=check(end)?);
+            }
+            None => parsed.push(check(parse(selection)?)?),
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Split the search given with `@-N` from the selections.
+fn split_search(selections: &[String]) -> Result<(Option<usize>, &[String]), String> {
+    match selections.split_first() {
+        Some((first, rest)) if first.starts_with('@') => {
+            let search = first
+                .strip_prefix("@-")
+                .and_then(|number| number.parse().ok())
+                .ok_or_else(|| format!("Invalid search {first:?}, expected `@-N` like `@-1`."))?;
+            Ok((Some(search), rest))
+        }
+        _ => Ok((None, selections)),
+    }
+}
+
+/// Path of a match to open from `cwd`. The relative paths are resolved against the directory
+/// of the search `root`, they are kept as is when vg runs from there.
+fn resolve_path(path: &[u8], root: Option<&path::Path>, cwd: &path::Path) -> Vec<u8> {
+    let relative = path::Path::new(OsStr::from_bytes(path));
+    match root {
+        Some(root) if relative.is_relative() && root != cwd => {
+            root.join(relative).as_os_str().as_bytes().to_vec()
+        }
+        _ => path.to_vec(),
+    }
+}
+
+/// Editor used when neither `--editor`, the configuration, `$VISUAL` nor `$EDITOR` give one.
+const FALLBACK_EDITOR: &str = "vi";
+
+/// Split the editor into the program and its fixed args, like `code --wait`. `None` if the
+/// quotes are unbalanced or there is no program.
+fn split_editor(editor: &str) -> Option<(String, Vec<String>)> {
+    let mut words = shlex::split(editor)?.into_iter();
+    let program = words.next()?;
+    Some((program, words.collect()))
+}
+
+/// Placeholders replaced by the location of a selection.
+static SELECTION_PLACEHOLDERS: [&str; 8] = [
+    "{PATH}",
+    "{ABSOLUTE_PATH}",
+    "{DIR}",
+    "{BASENAME}",
+    "{LINE}",
+    "{COLUMN}",
+    "{BYTE_COLUMN}",
+    "{MATCH}",
+];
+
+/// Replace the placeholders of `word` by their value. The values are bytes since paths and
+/// matches might not be valid UTF-8, they are not searched for placeholders.
+fn substitute(word: &str, values: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut substituted = vec![];
+    let mut rest = word;
+
+    'word: while let Some(c) = rest.chars().next() {
+        if c == '{' {
+            for (placeholder, value) in values {
+                if let Some(after) = rest.strip_prefix(placeholder) {
+                    substituted.extend_from_slice(value);
+                    rest = after;
+                    continue 'word;
+                }
+            }
+        }
+        substituted.extend_from_slice(&rest.as_bytes()[..c.len_utf8()]);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    substituted
+}
+
+/// Directory and file name of a path.
+fn split_path(path: &[u8]) -> (&[u8], &[u8]) {
+    match path.iter().rposition(|c| *c == b'/') {
+        Some(0) => (b"/", &path[1..]),
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => (b".", path),
+    }
+}
+
+/// Word separating the commands of a format, they are run one after the other like in a shell.
+const COMMAND_SEPARATOR: &str = "&&";
+
+/// Build the arguments of the editor from the words of the format. Each word is one argument,
+/// whatever the values of its placeholders.
+///
+/// `values` holds the placeholders that do not depend on the selection, like `{EDITOR}`.
+///
+/// The words using the placeholders of a selection are repeated for each selection, the other
+/// words are kept once: `{EDITOR} -g {PATH}:{LINE}` opens `code -g a.rs:1 b.rs:2`. Consecutive
+/// words are repeated together: `{EDITOR} +{LINE} {PATH}` opens `emacs +1 a.rs +2 b.rs`.
+fn editor_args(words: &[String], values: &[(&str, &[u8])], selections: &[Index]) -> Vec<CString> {
+    let uses_selection = |word: &String| SELECTION_PLACEHOLDERS.iter().any(|p| word.contains(p));
+    let cwd = env::current_dir().unwrap_or_default();
+    let mut args = vec![];
+
+    for group in words.chunk_by(|a, b| uses_selection(a) == uses_selection(b)) {
+        if !uses_selection(&group[0]) {
+            args.extend(group.iter().map(|word| {
+                CString::new(substitute(word, values)).expect("CString Failed to create")
+            }));
+            continue;
+        }
+
+        for index in selections {
+            let absolute_path = cwd.join(OsStr::from_bytes(&index.path));
+            let (dir, basename) = split_path(&index.path);
+            let (line, column, byte_column) = (
+                index.line.to_string(),
+                index.column.to_string(),
+                index.byte_column.to_string(),
+            );
+            let mut selection_values: Vec<(&str, &[u8])> = vec![
+                ("{PATH}", &index.path),
+                ("{ABSOLUTE_PATH}", absolute_path.as_os_str().as_bytes()),
+                ("{DIR}", dir),
+                ("{BASENAME}", basename),
+                ("{LINE}", line.as_bytes()),
+                ("{COLUMN}", column.as_bytes()),
+                ("{BYTE_COLUMN}", byte_column.as_bytes()),
+                ("{MATCH}", &index.submatch),
+            ];
+            selection_values.extend_from_slice(values);
+
+            for word in group {
+                args.push(
+                    CString::new(substitute(word, &selection_values))
+                        .expect("CString Failed to create"),
+                );
+            }
+        }
+    }
 
-    /// path to index state file of rgvg
-    #[arg(short, long, default_value = "~/.cgvg.idx")]
-    index_file: String,
-    /// Place match file of rgvg
-    #[arg(short, long, default_value = "~/.cgvg.match")]
-    match_file: String,
+    args
 }
 
 fn main() -> ExitCode {
@@ -50,48 +278,126 @@ fn main() -> ExitCode {
     let args = Args::parse();
     debug!("{args:?}");
 
-    // Find a text editor
-    let editor_path = match args.editor {
-        Some(editor) => editor,
-        None => match env::var("EDITOR") {
-            Ok(val) => val,
-            Err(_) => {
-                eprintln!("Failed to find and editor. Check the content of your $EDITOR environment variable or use the command line option `--editor`.");
-                std::process::exit(1);
-            }
-        },
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(1);
+        }
     };
+    debug!("{config:?}");
 
-    // Using `which` to check that the editor is in the path
-    let find_editor = Command::new("which")
-        .arg(&editor_path)
-        .output()
-        .expect("failed to execute process");
+    let (match_file, index_file, history_dir) =
+        match config.store_paths(args.match_file, args.index_file, args.scope) {
+            Ok(paths) => paths,
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::from(1);
+            }
+        };
 
-    if !find_editor.status.success() {
-        eprintln!("Could not find editor ($EDITOR={editor_path:}) in path.");
+    // Find a text editor
+    let editor = args
+        .editor
+        .or(config.vg.editor)
+        .or_else(|| env::var("VISUAL").ok().filter(|editor| !editor.is_empty()))
+        .or_else(|| env::var("EDITOR").ok().filter(|editor| !editor.is_empty()))
+        .unwrap_or_else(|| String::from(FALLBACK_EDITOR));
+    let Some((editor_path, editor_fixed_args)) = split_editor(&editor) else {
+        eprintln!("Invalid editor {editor:?}. Check the content of your $VISUAL and $EDITOR environment variables or use the command line option `--editor`.");
         return ExitCode::from(1);
-    }
+    };
+
+    // The resolved path is used to start the editor
+    let editor_program = match find_executable(&editor_path) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("Could not find editor: {e}.");
+            return ExitCode::from(1);
+        }
+    };
 
     // Finding the editor name to choose the command to open the file
-    let editor_name = if editor_path.starts_with('/') || editor_path.starts_with("./") {
-        editor_path.split('/').last().expect("Editor name")
-    } else {
-        &editor_path
+    let editor_name = editor_path.rsplit('/').next().expect("Editor name");
+
+    // `--server` implies that the running nvim is used
+    let instance = match args.server {
+        Some(_) => InstanceMode::Reuse,
+        None => args.instance.unwrap_or(config.vg.instance),
     };
+    let builtin = match instance {
+        InstanceMode::Reuse => reuse_formats(editor_name, args.server).or_else(|| {
+            debug!("no running instance of {editor_name} found, starting a new one");
+            spawn_formats(editor_name)
+        }),
+        InstanceMode::Spawn => spawn_formats(editor_name),
+    };
+
+    // The entries of the configuration take precedence over the builtin rules
+    let formats = config
+        .vg
+        .editors
+        .get(editor_name)
+        .map(Formats::from)
+        .unwrap_or_default()
+        .or(builtin);
+    debug!("{formats:?}");
 
-    let open_format = match args.format {
+    let open_format = match args.format.clone().or(formats.open) {
         Some(format) => format,
-        None => match editor_name {
-            "vim" | "vi" | "nvim" | "emacs" => String::from("{EDITOR} +{LINE} {PATH}"),
-            "code" | "codium" => String::from("{EDITOR} -g {PATH}:{LINE}"),
-            _ => {
-                panic!("No rule for editor: {editor_name:?}. You can use the `--format` option.");
-            }
-        },
+        None if args.quickfix => String::new(),
+        None => {
+            eprintln!(
+                "No rule for editor: {editor_name:?}. Use the `--format` option, or add a \
+                 `[vg.editors.{editor_name}]` section with a `format` to the configuration.\n\
+                 Editors known by vg: {}.",
+                known_editors().join(", ")
+            );
+            return ExitCode::from(1);
+        }
     };
 
-    let match_file = expand_path(&args.match_file).unwrap();
+    // Format opening several selections in one call, an explicit `--format` is used for each
+    // selection
+    let multi_format = match args.format {
+        Some(_) => None,
+        None => formats.multi,
+    };
+
+    let (search, selections) = match split_search(&args.selections) {
+        Ok((Some(_), _)) if args.search.is_some() => {
+            eprintln!("The search is given twice, with `--search` and `@-N`.");
+            return ExitCode::from(1);
+        }
+        Ok((search, selections)) => (search.or(args.search).unwrap_or(0), selections.to_vec()),
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(1);
+        }
+    };
+    if selections.is_empty() && !args.quickfix {
+        eprintln!("No selection to open.");
+        return ExitCode::from(1);
+    }
+
+    // The search 0 is the last one, the previous ones are in the history
+    let (match_file, index_file) = match search {
+        0 => (match_file, index_file),
+        search => {
+            let entries = previous_searches(&history_dir, &match_file);
+            match entries.map(|entries| entries.into_iter().nth(search - 1)) {
+                Ok(Some(entry)) => (entry.match_file, entry.index_file),
+                Ok(None) => {
+                    eprintln!("No search {search} in the history, `cg --history` lists them.");
+                    return ExitCode::from(1);
+                }
+                Err(e) => {
+                    eprintln!("{e}");
+                    return ExitCode::from(1);
+                }
+            }
+        }
+    };
 
     match fs::metadata(&match_file) {
         Ok(_) => {}
@@ -104,47 +410,345 @@ fn main() -> ExitCode {
         }
     }
 
-    let selected = args.seletion;
+    // The quickfix list has all the matches by default
+    let selections = match selections.is_empty() {
+        true => vec![String::from("all")],
+        false => selections,
+    };
+    // The store is mapped once, so the selections and the search information are read from
+    // the same search
+    let store = match Store::open(&match_file, &index_file) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(1);
+        }
+    };
+    let selected = match parse_selections(&selections, store.len()) {
+        Ok(selected) => selected,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(1);
+        }
+    };
+
+    let mut results = match selected
+        .iter()
+        .map(|idx| store.get(*idx))
+        .collect::<Result<Vec<Index>, CgVgError>>()
+    {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(1);
+        }
+    };
+
+    // Editors that cannot open several files at once, like vim, get several selections in their
+    // quickfix list
+    let quickfix = args.quickfix
+        || (results.len() > 1
+            && args.format.is_none()
+            && multi_format.is_none()
+            && formats.quickfix.is_some());
 
-    let result = match load_text(selected, &match_file) {
-        Ok(index) => index,
-        Err(CgVgError::LoadIndexOob(idx, len)) => {
+    // The pattern is not known for the results read from stdin
+    let info = store.info().clone();
+    drop(store);
+
+    // rg gives the paths relative to the directory of the search
+    let cwd = env::current_dir().unwrap_or_default();
+    let root = info.cwd.as_deref().map(path::Path::new);
+    for (index, idx) in results.iter_mut().zip(&selected) {
+        index.path = resolve_path(&index.path, root, &cwd);
+        let path = path::Path::new(OsStr::from_bytes(&index.path));
+        if !path.exists() {
+            let searched_from = root
+                .map(|root| format!(", the search was run from {}", root.display()))
+                .unwrap_or_default();
             eprintln!(
-                "Provided Index ({}) greater that number of possibilities ({}).",
-                idx, len
+                "The file {} of the selection {idx} does not exist anymore{searched_from}. Run cg \
+                 again to update the results.",
+                path.display()
             );
-            return ExitCode::from(1);
+            // The quickfix list can still be used for the other files
+            if !quickfix {
+                return ExitCode::from(1);
+            }
         }
-        Err(CgVgError::LoadIndexFormat) => {
-            eprintln!("Could not load select index. It might be a formating issue.",);
+    }
+
+    let format = match (quickfix, &formats.quickfix) {
+        (true, Some(format)) => format,
+        (true, None) => {
+            eprintln!("No quickfix rule for editor: {editor_name:?}. You can set a `quickfix_format` in the `[vg.editors.{editor_name}]` section of the configuration.");
             return ExitCode::from(1);
         }
+        (false, _) => multi_format.as_ref().unwrap_or(&open_format),
+    };
+    let Some(words) = shlex::split(format) else {
+        eprintln!("Invalid format {format:?}: unbalanced quotes.");
+        return ExitCode::from(1);
+    };
+    if words
+        .split(|word| word == COMMAND_SEPARATOR)
+        .any(|command| command.is_empty())
+    {
+        eprintln!("Invalid format {format:?}: empty command.");
+        return ExitCode::from(1);
+    }
+
+    let pattern = info.pattern.unwrap_or_default();
+    let mut values: Vec<(&str, &[u8])> = vec![
+        ("{EDITOR}", editor_program.as_bytes()),
+        ("{PATTERN}", pattern.as_bytes()),
+    ];
+
+    // The quickfix file is private to the user, in its runtime directory when there is one
+    let quickfix_dir = env::var_os("XDG_RUNTIME_DIR")
+        .map(path::PathBuf::from)
+        .filter(|dir| dir.is_absolute() && dir.is_dir())
+        .unwrap_or_else(env::temp_dir);
+    let quickfix_file;
+
+    let selection_groups: Vec<&[Index]> = if quickfix {
+        // A running editor might not be in the directory of vg, it is given absolute paths
+        let quickfix_results = match instance {
+            InstanceMode::Reuse => results
+                .iter()
+                .map(|index| Index {
+                    path: cwd
+                        .join(OsStr::from_bytes(&index.path))
+                        .as_os_str()
+                        .as_bytes()
+                        .to_vec(),
+                    ..index.clone()
+                })
+                .collect(),
+            InstanceMode::Spawn => results.clone(),
+        };
+        quickfix_file = match save_quickfix(&quickfix_results, &quickfix_dir) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!(
+                    "Could not write the quickfix file in {}: {e}",
+                    quickfix_dir.display()
+                );
+                return ExitCode::from(1);
+            }
+        };
+
+        values.push(("{QUICKFIX}", quickfix_file.as_bytes()));
+        vec![&[]]
+    } else if multi_format.is_some() {
+        // One call for all the selections
+        vec![&results]
+    } else {
+        // One call per selection
+        results.chunks(1).collect()
+    };
+
+    // The fixed args of the editor and the wait flag are given right after the program
+    let wait = formats.wait.filter(|_| args.wait || config.vg.wait);
+    let program_args = |segment: &[String]| -> Vec<CString> {
+        let mut program_args: Vec<String> = match segment[0].as_str() {
+            "{EDITOR}" => editor_fixed_args.clone(),
+            _ => Vec::new(),
+        };
+        if let Some(wait) = wait.as_ref().filter(|wait| !program_args.contains(wait)) {
+            program_args.push(wait.clone());
+        }
+        program_args
+            .into_iter()
+            .map(|arg| CString::new(arg).expect("CString Failed to create"))
+            .collect()
     };
 
-    // Replacing the placeholders
-    let mut command_args: String = open_format.replace("{LINE}", &result.1.to_string());
-    command_args = command_args.replace("{EDITOR}", &editor_path);
-    command_args = command_args.replace("{PATH}", &result.0);
+    let mut commands: Vec<Vec<CString>> = vec![];
+    for selections in selection_groups {
+        for segment in words.split(|word| word == COMMAND_SEPARATOR) {
+            let mut command = editor_args(segment, &values, selections);
+            command.splice(1..1, program_args(segment));
+            commands.push(command);
+        }
+    }
 
-    debug!("command_args: {}", command_args);
+    debug!("commands: {:?}", commands);
 
-    // Argument for excv, the first arg is the command name
-    let splitted_args: Vec<CString> = command_args
-        .split_whitespace()
-        .map(|arg| CString::new(arg).expect("CString Failed to create"))
-        .collect();
+    // The editors are opened one after the other, vg is replaced by the last one. The program is
+    // the first word of the format, usually {EDITOR}
+    let last = commands.pop().expect("at least one selection");
+    for command in commands {
+        let program = command[0].to_string_lossy();
+        let status = Command::new(OsStr::from_bytes(command[0].as_bytes()))
+            .args(
+                command[1..]
+                    .iter()
+                    .map(|arg| OsStr::from_bytes(arg.as_bytes())),
+            )
+            .status();
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                eprintln!("{program} exited with {status}.");
+                return ExitCode::from(1);
+            }
+            Err(e) => {
+                eprintln!("Could not run {program}: {e}");
+                return ExitCode::from(1);
+            }
+        }
+    }
 
-    let mut args_ptrs: Vec<*const libc::c_char> =
-        splitted_args.iter().map(|arg| arg.as_ptr()).collect();
+    let mut args_ptrs: Vec<*const libc::c_char> = last.iter().map(|arg| arg.as_ptr()).collect();
 
     args_ptrs.push(ptr::null());
 
-    let command = CString::new(editor_path).expect("cannot create cstring for program");
-
     unsafe {
         // Execvp looks for the path if the binary name is given
-        execvp(command.as_ptr(), args_ptrs.as_ptr());
+        execvp(last[0].as_ptr(), args_ptrs.as_ptr());
     }
 
-    return ExitCode::from(1);
+    eprintln!(
+        "Could not run {}: {}",
+        last[0].to_string_lossy(),
+        std::io::Error::last_os_error()
+    );
+    ExitCode::from(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_selections() {
+        assert_eq!(
+            vec![3, 7, 10, 11, 12],
+            parse_selections(&strings(&["3", "7", "10-12"]), 20).unwrap()
+        );
+        assert_eq!(
+            vec![0, 1, 2],
+            parse_selections(&strings(&["all"]), 3).unwrap()
+        );
+        assert!(parse_selections(&strings(&["12-10"]), 20).is_err());
+        assert!(parse_selections(&strings(&["foo"]), 20).is_err());
+        assert!(parse_selections(&strings(&["1-20"]), 20).is_err());
+        assert!(parse_selections(&strings(&["0-3000000000"]), 20).is_err());
+        assert!(parse_selections(&strings(&["all"]), 0).is_err());
+    }
+
+    #[test]
+    fn test_editor_args() {
+        let selections = vec![
+            Index::with_submatch(b"src/a.rs".to_vec(), 1, b"let a", 4, 5),
+            Index::new(b"b b.rs".to_vec(), 2),
+        ];
+        let to_str = |args: Vec<CString>| {
+            args.iter()
+                .map(|arg| arg.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let args = editor_args(
+            &strings(&["{EDITOR}", "-g", "{PATH}:{LINE}:{COLUMN}"]),
+            &[("{EDITOR}", b"code")],
+            &selections,
+        );
+        assert_eq!(
+            vec!["code", "-g", "src/a.rs:1:5", "b b.rs:2:1"],
+            to_str(args)
+        );
+
+        let args = editor_args(
+            &strings(&["{EDITOR}", "+{LINE}:{COLUMN}", "{PATH}", "--"]),
+            &[("{EDITOR}", b"emacs")],
+            &selections,
+        );
+        assert_eq!(
+            vec!["emacs", "+1:5", "src/a.rs", "+2:1", "b b.rs", "--"],
+            to_str(args)
+        );
+
+        let args = editor_args(&strings(&["{ABSOLUTE_PATH}"]), &[], &selections[..1]);
+        assert_eq!(
+            vec![env::current_dir()
+                .unwrap()
+                .join("src/a.rs")
+                .to_str()
+                .unwrap()],
+            to_str(args)
+        );
+
+        let args = editor_args(
+            &strings(&["{DIR}", "{BASENAME}", "{MATCH}", "/{PATTERN}", "{UNKNOWN}"]),
+            &[("{PATTERN}", b"{MATCH} a")],
+            &selections[..1],
+        );
+        assert_eq!(
+            vec!["src", "a.rs", "a", "/{MATCH} a", "{UNKNOWN}"],
+            to_str(args)
+        );
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let root = path::Path::new("/src/project");
+        assert_eq!(
+            b"/src/project/src/a.rs".to_vec(),
+            resolve_path(b"src/a.rs", Some(root), path::Path::new("/src/project/src"))
+        );
+        assert_eq!(
+            b"src/a.rs".to_vec(),
+            resolve_path(b"src/a.rs", Some(root), root)
+        );
+        assert_eq!(
+            b"/etc/hosts".to_vec(),
+            resolve_path(b"/etc/hosts", Some(root), path::Path::new("/"))
+        );
+        assert_eq!(
+            b"src/a.rs".to_vec(),
+            resolve_path(b"src/a.rs", None, path::Path::new("/"))
+        );
+    }
+
+    #[test]
+    fn test_split_search() {
+        let selections = strings(&["@-2", "14", "3"]);
+        assert_eq!(Ok((Some(2), &selections[1..])), split_search(&selections));
+        let selections = strings(&["14"]);
+        assert_eq!(Ok((None, &selections[..])), split_search(&selections));
+        assert_eq!(Ok((None, &[][..])), split_search(&[]));
+        assert!(split_search(&strings(&["@2", "14"])).is_err());
+        assert!(split_search(&strings(&["@-x"])).is_err());
+    }
+
+    #[test]
+    fn test_split_editor() {
+        assert_eq!(Some((String::from("nvim"), vec![])), split_editor("nvim"));
+        assert_eq!(
+            Some((String::from("/usr/bin/code"), strings(&["--wait"]))),
+            split_editor("/usr/bin/code --wait")
+        );
+        assert_eq!(
+            Some((String::from("emacsclient"), strings(&["-t", "-a", ""]))),
+            split_editor("emacsclient -t -a ''")
+        );
+        assert_eq!(None, split_editor("  "));
+        assert_eq!(None, split_editor("vim '"));
+    }
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!(
+            (&b"src/cg"[..], &b"main.rs"[..]),
+            split_path(b"src/cg/main.rs")
+        );
+        assert_eq!((&b"/"[..], &b"etc"[..]), split_path(b"/etc"));
+        assert_eq!((&b"."[..], &b"main.rs"[..]), split_path(b"main.rs"));
+    }
 }